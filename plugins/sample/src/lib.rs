@@ -0,0 +1,59 @@
+//! 示例插件，ABI 见主项目 `src/plugin.rs`
+use serde_json::{json, Value};
+
+#[no_mangle]
+pub extern "C" fn alloc(len: i32) -> i32 {
+    let mut buf = Vec::<u8>::with_capacity(len as usize);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr as i32
+}
+
+fn read_input(ptr: i32, len: i32) -> Value {
+    // SAFETY: 宿主通过 alloc 分配并写入了 len 字节
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    serde_json::from_slice(bytes).unwrap_or(Value::Null)
+}
+
+fn write_output(value: &Value) -> i64 {
+    let bytes = serde_json::to_vec(value).unwrap_or_default().leak();
+    ((bytes.as_ptr() as u32 as i64) << 32) | bytes.len() as u32 as i64
+}
+
+/// 转换：取角色串中的第一个 `id=` 值并转小写，如 "id=Admin,id=TATTOO" → "admin"
+#[no_mangle]
+pub extern "C" fn transform(ptr: i32, len: i32) -> i64 {
+    let input = read_input(ptr, len);
+    let value = input["value"]
+        .as_str()
+        .and_then(|s| s.split(',').next())
+        .and_then(|s| s.strip_prefix("id="))
+        .map(|s| Value::from(s.to_lowercase()))
+        .unwrap_or(Value::Null);
+    write_output(&json!({ "value": value }))
+}
+
+/// 阶段钩子：按 key 排序 query，写入 x-canonical-query header；重复的参数为数组，逐个输出
+#[no_mangle]
+pub extern "C" fn stage(ptr: i32, len: i32) -> i64 {
+    let mut input = read_input(ptr, len);
+    let mut pairs: Vec<String> = input["query"]
+        .as_object()
+        .map(|q| {
+            q.iter()
+                .flat_map(|(k, v)| {
+                    let values = match v {
+                        Value::Array(values) => values.clone(),
+                        v => vec![v.clone()],
+                    };
+                    values
+                        .into_iter()
+                        .map(move |v| format!("{}={}", k, v.as_str().unwrap_or_default()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    pairs.sort();
+    input["headers"]["x-canonical-query"] = Value::from(pairs.join("&"));
+    write_output(&json!({ "headers": input["headers"] }))
+}
@@ -0,0 +1,38 @@
+// 管理接口：查看各命名上游的熔断、并发和地址状态
+use axum::response::{IntoResponse, Json, Response};
+use serde_json::{json, Map, Value};
+
+use crate::config::GlobalConfig;
+use crate::{breaker, pool, upstream};
+
+/// 各上游的状态，按名称排序
+pub fn upstreams_status(global_config: &GlobalConfig) -> Value {
+    let mut names: Vec<&String> = global_config.upstreams.keys().collect();
+    names.sort();
+    let upstreams: Map<String, Value> = names
+        .into_iter()
+        .map(|name| {
+            let conf = &global_config.upstreams[name];
+            let mut status = json!({});
+            if conf.circuit_breaker.is_some() {
+                status["circuit_breaker"] = breaker::status(name);
+            }
+            if let Some(limiter) = upstream::limiter_status(name) {
+                status["concurrency"] = limiter;
+            }
+            if let Some(targets) = pool::status(name) {
+                status["targets"] = targets;
+            }
+            (name.clone(), status)
+        })
+        .collect();
+    json!({ "upstreams": upstreams })
+}
+
+/// 管理接口路径时返回响应
+pub fn handle(path: &str, global_config: &GlobalConfig) -> Option<Response> {
+    if global_config.admin_path.as_deref() != Some(path) {
+        return None;
+    }
+    Some(Json(upstreams_status(global_config)).into_response())
+}
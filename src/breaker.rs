@@ -0,0 +1,334 @@
+// 上游熔断器：关闭、打开、半开三种状态，状态保存在内存中，配置重新加载后保留
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Json, Response},
+};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::{Metrics, Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    // 正在进行的试探请求数
+    HalfOpen { trials: usize },
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+struct Breaker {
+    state: State,
+    consecutive_failures: u32,
+    // 最近请求的结果，true 为失败
+    outcomes: VecDeque<bool>,
+    opened_total: u64,
+    rejected_total: u64,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker {
+            state: State::Closed,
+            consecutive_failures: 0,
+            outcomes: VecDeque::new(),
+            opened_total: 0,
+            rejected_total: 0,
+        }
+    }
+}
+
+impl Breaker {
+    fn error_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        self.outcomes.iter().filter(|f| **f).count() as f64 / self.outcomes.len() as f64
+    }
+
+    fn open(&mut self, name: &str, conf: &CircuitBreakerConfig) {
+        event!(Level::WARN, "Circuit breaker for upstream {} opened", name);
+        self.state = State::Open {
+            until: Instant::now() + Duration::from_secs(conf.open_secs),
+        };
+        self.opened_total += 1;
+    }
+
+    fn close(&mut self) {
+        self.state = State::Closed;
+        self.consecutive_failures = 0;
+        self.outcomes.clear();
+    }
+}
+
+fn breakers() -> &'static Mutex<HashMap<String, Breaker>> {
+    static BREAKERS: OnceLock<Mutex<HashMap<String, Breaker>>> = OnceLock::new();
+    BREAKERS.get_or_init(Default::default)
+}
+
+/// 熔断器打开，retry_after 后可以重试
+#[derive(Debug)]
+pub struct Open {
+    pub retry_after: Duration,
+}
+
+/// 放行的请求，需要用 record 记录结果；未记录就丢弃时释放半开的试探名额
+pub struct Guard {
+    name: String,
+    half_open: bool,
+    recorded: bool,
+}
+
+/// 检查熔断状态，打开时拒绝请求
+pub fn acquire(name: &str, conf: &CircuitBreakerConfig) -> Result<Guard, Open> {
+    let mut breakers = breakers().lock().unwrap();
+    let breaker = breakers.entry(name.to_string()).or_default();
+    let now = Instant::now();
+    let half_open = match breaker.state {
+        State::Closed => false,
+        State::Open { until } if until > now => {
+            breaker.rejected_total += 1;
+            return Err(Open {
+                retry_after: until - now,
+            });
+        }
+        State::Open { .. } => {
+            event!(
+                Level::INFO,
+                "Circuit breaker for upstream {} half-open",
+                name
+            );
+            breaker.state = State::HalfOpen { trials: 1 };
+            true
+        }
+        State::HalfOpen { trials } if trials < conf.half_open_requests.max(1) => {
+            breaker.state = State::HalfOpen { trials: trials + 1 };
+            true
+        }
+        State::HalfOpen { .. } => {
+            breaker.rejected_total += 1;
+            return Err(Open {
+                retry_after: Duration::from_secs(1),
+            });
+        }
+    };
+    Ok(Guard {
+        name: name.to_string(),
+        half_open,
+        recorded: false,
+    })
+}
+
+impl Guard {
+    /// 记录请求结果，达到阈值时打开熔断
+    pub fn record(mut self, conf: &CircuitBreakerConfig, failed: bool) {
+        self.recorded = true;
+        let mut breakers = breakers().lock().unwrap();
+        let breaker = breakers.entry(self.name.clone()).or_default();
+        match breaker.state {
+            State::HalfOpen { .. } if failed => breaker.open(&self.name, conf),
+            State::HalfOpen { .. } => {
+                event!(
+                    Level::INFO,
+                    "Circuit breaker for upstream {} closed",
+                    self.name
+                );
+                breaker.close();
+            }
+            State::Open { .. } => {}
+            State::Closed => {
+                breaker.consecutive_failures = if failed {
+                    breaker.consecutive_failures + 1
+                } else {
+                    0
+                };
+                breaker.outcomes.push_back(failed);
+                while breaker.outcomes.len() > conf.window.max(1) {
+                    breaker.outcomes.pop_front();
+                }
+                let rate_exceeded = conf.error_rate.is_some_and(|rate| {
+                    breaker.outcomes.len() >= conf.min_requests && breaker.error_rate() >= rate
+                });
+                if breaker.consecutive_failures >= conf.consecutive_failures || rate_exceeded {
+                    breaker.open(&self.name, conf);
+                    breaker.consecutive_failures = 0;
+                    breaker.outcomes.clear();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if !self.half_open || self.recorded {
+            return;
+        }
+        let mut breakers = breakers().lock().unwrap();
+        if let Some(breaker) = breakers.get_mut(&self.name) {
+            if let State::HalfOpen { trials } = breaker.state {
+                breaker.state = State::HalfOpen {
+                    trials: trials.saturating_sub(1),
+                };
+            }
+        }
+    }
+}
+
+/// 上游结果是否计为失败
+pub fn is_failure(
+    conf: &CircuitBreakerConfig,
+    result: &Result<reqwest::Response, reqwest::Error>,
+) -> bool {
+    match result {
+        Ok(response) => conf.failure_status.contains(&response.status().as_u16()),
+        Err(_) => true,
+    }
+}
+
+/// 熔断打开时的响应：配置的 fallback，默认 503
+pub fn fallback_response(conf: &CircuitBreakerConfig, open: &Open) -> Response {
+    let mut response = match &conf.fallback {
+        Some(fallback) => crate::static_response(fallback, &[]),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"error": "upstream_unavailable"})),
+        )
+            .into_response(),
+    };
+    let retry_after = open.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+/// 上游的熔断状态，未请求过时为关闭
+pub fn status(name: &str) -> Value {
+    let breakers = breakers().lock().unwrap();
+    let Some(breaker) = breakers.get(name) else {
+        return json!({"state": "closed"});
+    };
+    let mut status = json!({
+        "state": breaker.state.name(),
+        "consecutive_failures": breaker.consecutive_failures,
+        "error_rate": breaker.error_rate(),
+        "opened_total": breaker.opened_total,
+        "rejected_total": breaker.rejected_total,
+    });
+    if let State::Open { until } = breaker.state {
+        status["retry_after_secs"] =
+            json!(until.saturating_duration_since(Instant::now()).as_secs());
+    }
+    status
+}
+
+/// 输出各上游的熔断状态、打开和拒绝次数
+pub fn write_metrics(metrics: &mut Metrics) {
+    let breakers = breakers().lock().unwrap();
+    let mut names: Vec<&String> = breakers.keys().collect();
+    names.sort();
+    let states: Vec<Sample> = names
+        .iter()
+        .flat_map(|name| {
+            let current = breakers[*name].state.name();
+            ["closed", "open", "half_open"].map(|state| {
+                (
+                    vec![("upstream", name.as_str()), ("state", state)],
+                    if state == current { 1.0 } else { 0.0 },
+                )
+            })
+        })
+        .collect();
+    let samples = |value: fn(&Breaker) -> f64| -> Vec<Sample> {
+        names
+            .iter()
+            .map(|name| (vec![("upstream", name.as_str())], value(&breakers[*name])))
+            .collect()
+    };
+    metrics.gauge(
+        "sso_adapter_upstream_circuit_state",
+        "Circuit breaker state per upstream, 1 for the current state.",
+        &states,
+    );
+    metrics.counter(
+        "sso_adapter_upstream_circuit_opened_total",
+        "Times the circuit breaker opened.",
+        &samples(|b| b.opened_total as f64),
+    );
+    metrics.counter(
+        "sso_adapter_upstream_circuit_rejected_total",
+        "Requests failed fast because the circuit breaker was open.",
+        &samples(|b| b.rejected_total as f64),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, conf: &CircuitBreakerConfig, failed: bool) {
+        acquire(name, conf).unwrap().record(conf, failed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_half_open_and_close() {
+        let conf: CircuitBreakerConfig =
+            serde_yaml::from_str("{consecutive_failures: 3, open_secs: 10}").unwrap();
+        record("breaker-a", &conf, true);
+        record("breaker-a", &conf, false);
+        record("breaker-a", &conf, true);
+        record("breaker-a", &conf, true);
+        assert_eq!(status("breaker-a")["state"], "closed");
+        record("breaker-a", &conf, true);
+        assert_eq!(status("breaker-a")["state"], "open");
+        let open = acquire("breaker-a", &conf).err().unwrap();
+        assert_eq!(open.retry_after, Duration::from_secs(10));
+
+        // 半开只放行一个试探请求，未记录结果时释放名额
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let trial = acquire("breaker-a", &conf).unwrap();
+        assert!(acquire("breaker-a", &conf).is_err());
+        drop(trial);
+        // 试探失败重新打开
+        record("breaker-a", &conf, true);
+        assert_eq!(status("breaker-a")["state"], "open");
+        tokio::time::advance(Duration::from_secs(10)).await;
+        record("breaker-a", &conf, false);
+        assert_eq!(status("breaker-a")["state"], "closed");
+        assert_eq!(status("breaker-a")["opened_total"], 2);
+        assert_eq!(status("breaker-a")["rejected_total"], 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_on_error_rate() {
+        let conf: CircuitBreakerConfig = serde_yaml::from_str(
+            "{consecutive_failures: 100, error_rate: 0.5, window: 10, min_requests: 6}",
+        )
+        .unwrap();
+        for failed in [true, false, true, false, true] {
+            record("breaker-b", &conf, failed);
+        }
+        assert_eq!(status("breaker-b")["state"], "closed");
+        record("breaker-b", &conf, false);
+        assert_eq!(status("breaker-b")["state"], "open");
+        assert!(crate::metrics::render().contains(
+            "sso_adapter_upstream_circuit_state{upstream=\"breaker-b\",state=\"open\"} 1"
+        ));
+    }
+}
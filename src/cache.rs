@@ -0,0 +1,64 @@
+// 按路径和修改时间缓存从文件加载的数据，配置每次请求重新加载时不必重复读取和解析
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+type Entries<T> = Mutex<HashMap<PathBuf, (SystemTime, Arc<T>)>>;
+
+/// 文件修改后下一次读取时重新加载
+pub struct FileCache<T>(OnceLock<Entries<T>>);
+
+impl<T> FileCache<T> {
+    pub const fn new() -> Self {
+        FileCache(OnceLock::new())
+    }
+
+    pub fn get(
+        &self,
+        path: impl AsRef<Path>,
+        load: impl FnOnce(&Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Arc<T>> {
+        let path = path.as_ref();
+        let modified = std::fs::metadata(path)?.modified()?;
+        let entries = self.0.get_or_init(Default::default);
+        if let Some((loaded, value)) = entries.lock().unwrap().get(path) {
+            if *loaded == modified {
+                return Ok(value.clone());
+            }
+        }
+        let value = Arc::new(load(path)?);
+        entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, value.clone()));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reload_after_modification() {
+        static CACHE: FileCache<String> = FileCache::new();
+        let path = std::env::temp_dir().join(format!("file-cache-{}.txt", std::process::id()));
+        std::fs::write(&path, "v1").unwrap();
+        let load = |p: &Path| Ok(std::fs::read_to_string(p)?);
+        assert_eq!(*CACHE.get(&path, load).unwrap(), "v1");
+
+        // 修改时间不变时使用缓存
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "v2").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(*CACHE.get(&path, load).unwrap(), "v1");
+
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(*CACHE.get(&path, load).unwrap(), "v2");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
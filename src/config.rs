@@ -1,5 +1,6 @@
 #![allow(dead_code, unused_imports)]
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
@@ -9,6 +10,13 @@ pub struct AppConfig {
     pub use_mode: UseMode,
     pub dify_host: Option<String>,
     pub self_host: String,
+    // 需要处理 body 时允许读入内存的最大字节数
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+fn default_max_body_size() -> usize {
+    10 * 1024 * 1024
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -18,6 +26,457 @@ pub enum UseMode {
     Normal,
 }
 
+/// mapping 文件中路径以外的全局配置
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GlobalConfig {
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+    // 命名上游，路由通过 request.upstream 引用
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    // 浏览器 SSO 流程的服务端会话
+    #[serde(default)]
+    pub session: Option<SessionConfig>,
+    // Prometheus 格式指标的路径，如 /metrics，未配置时不提供
+    #[serde(default)]
+    pub metrics_path: Option<String>,
+    // 查看上游熔断和并发状态的管理接口路径，如 /admin/upstreams，未配置时不提供
+    #[serde(default)]
+    pub admin_path: Option<String>,
+}
+
+// cookie 只保存签名后的会话 id，数据保存在服务端
+#[derive(Debug, Deserialize, Clone)]
+pub struct SessionConfig {
+    // cookie 签名密钥
+    pub secret: String,
+    #[serde(default = "default_session_cookie")]
+    pub cookie_name: String,
+    #[serde(default)]
+    pub store: SessionStoreType,
+    // file 存储的目录
+    #[serde(default = "default_session_dir")]
+    pub dir: String,
+    #[serde(default = "default_session_ttl")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_session_secure")]
+    pub secure: bool,
+    #[serde(default = "default_session_same_site")]
+    pub same_site: String,
+    // 会话数据写入的变量前缀，映射中用 `!var session.state` 读写
+    #[serde(default = "default_session_var")]
+    pub var: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreType {
+    #[default]
+    Memory,
+    File,
+}
+
+fn default_session_cookie() -> String {
+    "sso_adapter_session".to_string()
+}
+
+fn default_session_dir() -> String {
+    "data/sessions".to_string()
+}
+
+fn default_session_ttl() -> u64 {
+    600
+}
+
+fn default_session_secure() -> bool {
+    true
+}
+
+fn default_session_same_site() -> String {
+    "Lax".to_string()
+}
+
+fn default_session_var() -> String {
+    "session".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamConfig {
+    // 上游地址，未配置时使用路由默认的地址
+    #[serde(default)]
+    pub url: Option<String>,
+    // 多个上游地址，配置后按 balance 选择，忽略 url
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    // 主动健康检查，未配置时只按请求结果摘除
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    // 被动摘除：连续失败的地址暂时不再选择
+    #[serde(default)]
+    pub ejection: EjectionConfig,
+    // 调用上游时获取并注入 access token
+    #[serde(default)]
+    pub auth: Option<UpstreamAuthConfig>,
+    // 映射完成后对最终请求签名
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    // 最大并发请求数，未配置时不限制
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    // 并发已满时最多排队的请求数，超出时直接返回 503
+    #[serde(default)]
+    pub max_queue: usize,
+    // 排队等待的最长时间，超时返回 503
+    #[serde(default = "default_upstream_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    // 熔断，打开时不再请求上游，直接返回 fallback
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+fn default_upstream_queue_timeout_ms() -> u64 {
+    1000
+}
+
+// 多地址的负载均衡策略
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    // 按 header 或 cookie 的值一致性哈希，都读取不到时使用客户端 IP
+    ConsistentHash {
+        #[serde(default)]
+        header: Option<String>,
+        #[serde(default)]
+        cookie: Option<String>,
+    },
+}
+
+// 主动健康检查：定期请求每个地址的 path
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    // 连续成功多少次恢复
+    #[serde(default = "default_health_check_threshold")]
+    pub healthy_threshold: u32,
+    // 连续失败多少次标记为不健康
+    #[serde(default = "default_health_check_threshold")]
+    pub unhealthy_threshold: u32,
+    // 视为健康的状态码，为空时为 2xx
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+}
+
+fn default_health_check_path() -> String {
+    "/health".to_string()
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_health_check_threshold() -> u32 {
+    2
+}
+
+// 被动摘除，consecutive_failures 为 0 时关闭
+#[derive(Debug, Deserialize, Clone)]
+pub struct EjectionConfig {
+    #[serde(default = "default_ejection_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "default_ejection_secs")]
+    pub eject_secs: u64,
+    // 计为失败的状态码，连接失败总是计为失败
+    #[serde(default = "default_ejection_failure_status")]
+    pub failure_status: Vec<u16>,
+}
+
+impl Default for EjectionConfig {
+    fn default() -> Self {
+        EjectionConfig {
+            consecutive_failures: default_ejection_consecutive_failures(),
+            eject_secs: default_ejection_secs(),
+            failure_status: default_ejection_failure_status(),
+        }
+    }
+}
+
+fn default_ejection_consecutive_failures() -> u32 {
+    3
+}
+
+fn default_ejection_secs() -> u64 {
+    30
+}
+
+fn default_ejection_failure_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+// 熔断器：连续失败或最近请求的错误率达到阈值时打开，open_secs 后半开放行试探请求
+#[derive(Debug, Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    // 连续失败次数阈值
+    #[serde(default = "default_breaker_consecutive_failures")]
+    pub consecutive_failures: u32,
+    // 最近 window 个请求的错误率阈值（0-1），请求数不少于 min_requests 时生效
+    #[serde(default)]
+    pub error_rate: Option<f64>,
+    #[serde(default = "default_breaker_window")]
+    pub window: usize,
+    #[serde(default = "default_breaker_min_requests")]
+    pub min_requests: usize,
+    // 打开后经过多久进入半开
+    #[serde(default = "default_breaker_open_secs")]
+    pub open_secs: u64,
+    // 半开时同时放行的试探请求数，试探成功后关闭，失败后重新打开
+    #[serde(default = "default_breaker_half_open_requests")]
+    pub half_open_requests: usize,
+    // 计为失败的上游状态码，连接失败总是计为失败
+    #[serde(default = "default_breaker_failure_status")]
+    pub failure_status: Vec<u16>,
+    // 打开时的响应，未配置时返回 503
+    #[serde(default)]
+    pub fallback: Option<StaticResponse>,
+}
+
+fn default_breaker_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_breaker_window() -> usize {
+    20
+}
+
+fn default_breaker_min_requests() -> usize {
+    10
+}
+
+fn default_breaker_open_secs() -> u64 {
+    30
+}
+
+fn default_breaker_half_open_requests() -> usize {
+    1
+}
+
+fn default_breaker_failure_status() -> Vec<u16> {
+    vec![500, 502, 503, 504]
+}
+
+// 请求签名方案
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "scheme", rename_all = "lowercase")]
+pub enum SigningConfig {
+    Hmac(HmacSigningConfig),
+    #[serde(rename = "aws_sigv4")]
+    AwsSigV4(AwsSigV4Config),
+}
+
+// 通用 HMAC 签名：按 canonical 模板逐行拼接后签名
+#[derive(Debug, Deserialize, Clone)]
+pub struct HmacSigningConfig {
+    pub secret: String,
+    #[serde(default)]
+    pub key_id: Option<String>,
+    #[serde(default)]
+    pub algorithm: HmacAlgorithm,
+    // 可用 {method} {path} {query} {body_sha256} {timestamp} {nonce} {key_id} {header.名称}
+    #[serde(default = "default_hmac_canonical")]
+    pub canonical: Vec<String>,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    #[serde(default = "default_hmac_signature_header")]
+    pub signature_header: String,
+    // 签名 header 的值，可用 {signature} {key_id} {timestamp} {nonce}
+    #[serde(default = "default_hmac_signature_format")]
+    pub signature_format: String,
+    // 为空时不发送对应 header
+    #[serde(default = "default_hmac_timestamp_header")]
+    pub timestamp_header: Option<String>,
+    #[serde(default)]
+    pub nonce_header: Option<String>,
+    #[serde(default)]
+    pub key_id_header: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+fn default_hmac_canonical() -> Vec<String> {
+    ["{method}", "{path}", "{query}", "{timestamp}", "{body_sha256}"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_hmac_signature_header() -> String {
+    "X-Signature".to_string()
+}
+
+fn default_hmac_signature_format() -> String {
+    "{signature}".to_string()
+}
+
+fn default_hmac_timestamp_header() -> Option<String> {
+    Some("X-Timestamp".to_string())
+}
+
+// AWS Signature Version 4
+#[derive(Debug, Deserialize, Clone)]
+pub struct AwsSigV4Config {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    #[serde(default)]
+    pub session_token: Option<String>,
+    pub region: String,
+    pub service: String,
+    // 发送 x-amz-content-sha256（S3 需要）
+    #[serde(default)]
+    pub content_sha256_header: bool,
+    // 不对 body 计算摘要，流式转发的请求使用
+    #[serde(default)]
+    pub unsigned_payload: bool,
+}
+
+// client credentials 模式获取 access token
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamAuthConfig {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    #[serde(default)]
+    pub client_auth: OAuth2ClientAuth,
+    // 注入的 header 及值的前缀
+    #[serde(default = "default_upstream_auth_header")]
+    pub header: String,
+    #[serde(default = "default_upstream_auth_prefix")]
+    pub prefix: String,
+    // 距过期不足该秒数时提前刷新
+    #[serde(default = "default_upstream_auth_refresh_before")]
+    pub refresh_before_secs: u64,
+    // token 响应没有 expires_in 时的有效期
+    #[serde(default = "default_upstream_auth_expires_in")]
+    pub default_expires_in: u64,
+}
+
+fn default_upstream_auth_header() -> String {
+    "Authorization".to_string()
+}
+
+fn default_upstream_auth_prefix() -> String {
+    "Bearer ".to_string()
+}
+
+fn default_upstream_auth_refresh_before() -> u64 {
+    60
+}
+
+fn default_upstream_auth_expires_in() -> u64 {
+    300
+}
+
+/// 解析 mapping 文件：以 / 开头的 key 为路径配置，其余为全局配置
+pub fn parse_mapping(
+    content: &str,
+) -> anyhow::Result<(HashMap<String, PathConfig>, GlobalConfig)> {
+    let root: serde_yaml::Mapping = serde_yaml::from_str(content)?;
+    let (mut paths, mut global) = (serde_yaml::Mapping::new(), serde_yaml::Mapping::new());
+    for (key, value) in root {
+        if key.as_str().is_some_and(|k| k.starts_with('/')) {
+            paths.insert(key, value);
+        } else {
+            global.insert(key, value);
+        }
+    }
+    Ok((
+        serde_yaml::from_value(serde_yaml::Value::Mapping(paths))?,
+        serde_yaml::from_value(serde_yaml::Value::Mapping(global))?,
+    ))
+}
+
+// 合成的 OIDC discovery 文档和 JWKS
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcConfig {
+    // 默认 https://{self_host}
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // 未配置时使用 oauth2_adapter 对应端点的路由
+    #[serde(default)]
+    pub authorization_path: Option<String>,
+    #[serde(default)]
+    pub token_path: Option<String>,
+    #[serde(default)]
+    pub userinfo_path: Option<String>,
+    #[serde(default = "default_oidc_jwks_path")]
+    pub jwks_path: String,
+    #[serde(default)]
+    pub jwks: Vec<JwkFile>,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes_supported: Vec<String>,
+    // 上游支持的 grant_type
+    #[serde(default = "default_oidc_grant_types")]
+    pub grant_types_supported: Vec<String>,
+    // 上游校验 PKCE 时开启，只声明 S256
+    #[serde(default)]
+    pub pkce: bool,
+    // 合并到 discovery 文档的其他字段
+    #[serde(default)]
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+fn default_oidc_jwks_path() -> String {
+    "/.well-known/jwks.json".to_string()
+}
+
+fn default_oidc_grant_types() -> Vec<String> {
+    vec!["authorization_code".to_string()]
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string(), "email".to_string()]
+}
+
+// 公钥文件：jwk / jwks json，或 RSA、P-256 公钥 pem
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwkFile {
+    pub file: String,
+    // pem 的 kid，默认为文件名
+    #[serde(default)]
+    pub kid: Option<String>,
+    #[serde(default)]
+    pub alg: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct PathConfig {
     pub request: RequestMapConfig,
@@ -30,6 +489,158 @@ pub struct RequestMapConfig {
     pub method_mapping: Option<MethodMapping>,
     pub body_conversion: Option<BodyConversion>,
     pub mix_mappings: Vec<MixMapping>,
+    // required 字段缺失时的响应，body 中的 {field} 替换为缺失字段名
+    #[serde(default)]
+    pub missing_response: Option<StaticResponse>,
+    // mix_mappings 之后执行的脚本
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+    // 脚本之后执行的 wasm 插件
+    #[serde(default)]
+    pub plugin: Option<PluginConfig>,
+    // 判断是否流式请求的字段，如 `stream: !bodyfield stream`
+    #[serde(default)]
+    pub stream: Option<MixSource>,
+    // websocket 客户端发往上游的消息映射
+    #[serde(default)]
+    pub message: Option<MessageMapConfig>,
+    // 覆盖全局 max_body_size
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    // 转发前校验 Authorization: Bearer 中的 JWT
+    #[serde(default)]
+    pub jwt: Option<JwtAuthConfig>,
+    // 转发前校验 API key 或 Basic 凭据
+    #[serde(default)]
+    pub api_key: Option<ApiKeyAuthConfig>,
+    // 引用全局 upstreams 中的上游
+    #[serde(default)]
+    pub upstream: Option<String>,
+    // 令牌桶限流
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // 转发失败时的重试策略
+    #[serde(default)]
+    pub retry: Option<RetryConfig>,
+}
+
+// 重试策略，默认只重试幂等方法，重试时重放已读入内存的请求 body
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    // 总尝试次数，包括第一次请求
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    // 第一次重试前的等待时间，之后每次翻倍，实际等待在其一半到全部之间随机
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    // 需要重试的上游状态码
+    #[serde(default = "default_retry_on_status")]
+    pub on_status: Vec<u16>,
+    // 连接失败时重试
+    #[serde(default = "default_retry_on_connect_error")]
+    pub on_connect_error: bool,
+    // 允许重试 POST，上游需要能处理重复请求
+    #[serde(default)]
+    pub retry_post: bool,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    100
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    2000
+}
+
+fn default_retry_on_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_retry_on_connect_error() -> bool {
+    true
+}
+
+// 令牌桶限流，按客户端 IP、header 或映射后的身份分别计数
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    // 每秒补充的令牌数
+    pub rate: f64,
+    // 桶容量，默认为 rate 向上取整
+    #[serde(default)]
+    pub burst: Option<u32>,
+    // 限流 key 的来源，如 `!header X-Forwarded-For`、`!var identity.id`，读取不到时使用客户端 IP
+    #[serde(default)]
+    pub key: Option<MixSource>,
+}
+
+// JWT bearer 认证，issuers、audiences 为空时不校验对应声明
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtAuthConfig {
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    // 验签公钥：pem 或 jwk/jwks 文件
+    #[serde(default)]
+    pub jwks: Vec<JwkFile>,
+    // HS256/HS384/HS512 的共享密钥
+    #[serde(default)]
+    pub secret: Option<String>,
+    // 允许的签名算法，为空时按配置的密钥推断
+    #[serde(default)]
+    pub algorithms: Vec<jsonwebtoken::Algorithm>,
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway_secs: u64,
+    // 声明写入的变量前缀，映射中用 `!var claims.email` 读取
+    #[serde(default = "default_claims_var")]
+    pub claims_var: String,
+}
+
+// API key / Basic 认证，凭据文件中只保存 sha256 摘要
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyAuthConfig {
+    pub credentials_file: String,
+    // 读取 API key 的位置，如 `!header X-API-Key`、`!query api_key`
+    #[serde(default = "default_api_key_sources")]
+    pub sources: Vec<MixSource>,
+    // 是否接受 Authorization: Basic
+    #[serde(default = "default_api_key_basic")]
+    pub basic: bool,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    // 身份写入的变量前缀，映射中用 `!var identity.id` 读取
+    #[serde(default = "default_identity_var")]
+    pub identity_var: String,
+}
+
+fn default_api_key_sources() -> Vec<MixSource> {
+    vec![MixSource::Header("X-API-Key".to_string())]
+}
+
+fn default_api_key_basic() -> bool {
+    true
+}
+
+fn default_realm() -> String {
+    "sso-adapter".to_string()
+}
+
+fn default_identity_var() -> String {
+    "identity".to_string()
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_claims_var() -> String {
+    "claims".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -37,15 +648,188 @@ pub struct ResponseMapConfig {
     pub method_mapping: Option<MethodMapping>,
     pub body_conversion: Option<BodyConversion>,
     pub mix_mappings: Vec<MixMapping>,
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+    #[serde(default)]
+    pub plugin: Option<PluginConfig>,
+    #[serde(default)]
+    pub sse: SseConfig,
+    // websocket 上游发往客户端的消息映射
+    #[serde(default)]
+    pub message: Option<MessageMapConfig>,
+    // 声明映射，在 mix_mappings 之前执行
+    #[serde(default)]
+    pub claims: Option<ClaimsConfig>,
+}
+
+// userinfo 类响应的声明映射：按 map 生成新的 body，keep_unmapped 时保留未映射的字段
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClaimsConfig {
+    #[serde(default)]
+    pub keep_unmapped: bool,
+    pub map: std::collections::BTreeMap<String, ClaimRule>,
+}
+
+// 声明来源，简写为来源字段名
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ClaimRule {
+    From(String),
+    Rule {
+        #[serde(default)]
+        from: Option<String>,
+        // 常量，或来源缺失时的默认值
+        #[serde(default)]
+        value: Option<serde_json::Value>,
+        #[serde(default)]
+        transformations: Vec<Transformation>,
+        #[serde(default)]
+        required: bool,
+    },
+}
+
+// websocket 消息映射，只作用于 json 文本消息
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MessageMapConfig {
+    #[serde(default)]
+    pub mix_mappings: Vec<MixMapping>,
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+    #[serde(default)]
+    pub plugin: Option<PluginConfig>,
+}
+
+// sse 转发配置，空闲超过 keepalive_secs 时发送注释心跳，0 关闭
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SseConfig {
+    #[serde(default = "default_sse_keepalive_secs")]
+    pub keepalive_secs: u64,
+    #[serde(default = "default_sse_keepalive_comment")]
+    pub keepalive_comment: String,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            keepalive_secs: default_sse_keepalive_secs(),
+            keepalive_comment: default_sse_keepalive_comment(),
+        }
+    }
+}
+
+fn default_sse_keepalive_secs() -> u64 {
+    15
+}
+
+fn default_sse_keepalive_comment() -> String {
+    "keep-alive".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ServiceType {
     Dify,
     SSO,
     Redirect(Option<String>),
     SSE(String),
+    // OpenAI chat completions 协议适配到 Dify
+    OpenAi(OpenAiAdapterConfig),
+    // 非标准 OAuth2 上游适配为 RFC 6749 / OIDC 端点
+    #[serde(rename = "oauth2_adapter")]
+    OAuth2Adapter(OAuth2AdapterConfig),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct OAuth2AdapterConfig {
+    pub endpoint: OAuth2Endpoint,
+    // 上游地址，未配置时使用 sso_url
+    #[serde(default)]
+    pub upstream_url: Option<String>,
+    // 上游路径，未配置时使用请求路径
+    #[serde(default)]
+    pub upstream_path: Option<String>,
+    // 调用上游的方法，默认 post（authorize 为浏览器重定向，不使用）
+    #[serde(default)]
+    pub upstream_method: OAuth2Method,
+    // 参数放在上游请求的位置
+    #[serde(default)]
+    pub params_in: OAuth2ParamLocation,
+    // 客户端凭证发给上游的方式
+    #[serde(default)]
+    pub client_auth: OAuth2ClientAuth,
+    // userinfo 的 access token 发给上游的位置，query/form 时参数名为 access_token
+    #[serde(default)]
+    pub token_in: OAuth2TokenLocation,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuth2Endpoint {
+    Token,
+    Authorize,
+    Userinfo,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuth2Method {
+    Get,
+    #[default]
+    Post,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuth2ParamLocation {
+    Query,
+    #[default]
+    Form,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuth2ClientAuth {
+    // client_id/client_secret 作为参数
+    #[default]
+    Params,
+    // Authorization: Basic
+    Basic,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuth2TokenLocation {
+    #[default]
+    Header,
+    Query,
+    Form,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct OpenAiAdapterConfig {
+    #[serde(default = "default_openai_chat_path")]
+    pub chat_path: String,
+    // 响应中的 model，未配置时使用请求中的 model
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default = "default_openai_user")]
+    pub default_user: String,
+    // system 消息写入的 Dify inputs 字段
+    #[serde(default)]
+    pub system_input: Option<String>,
+    // 是否将历史消息拼接进 query（未使用 conversation_id 时）
+    #[serde(default)]
+    pub include_history: bool,
+}
+
+fn default_openai_chat_path() -> String {
+    "/v1/chat-messages".to_string()
+}
+
+fn default_openai_user() -> String {
+    "openai-facade".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
@@ -62,6 +846,26 @@ pub struct MixMapping {
     pub action: MixAction,
     #[serde(default)]
     pub transformations: Option<Vec<Transformation>>,
+    // 源字段缺失（或转换结果为空）时使用的默认值
+    #[serde(default)]
+    pub default: Option<serde_json::Value>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+// 固定响应
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct StaticResponse {
+    #[serde(default = "default_static_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_static_status() -> u16 {
+    400
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -78,6 +882,100 @@ pub enum Transformation {
     Merge,
     Lowercase,
     Uppercase,
+    // 字典映射：内联 map 与 file（yaml/csv）合并，未命中时使用 default
+    Lookup {
+        #[serde(default)]
+        map: Option<serde_yaml::Mapping>,
+        #[serde(default)]
+        file: Option<String>,
+        #[serde(default)]
+        mode: LookupMode,
+        #[serde(default)]
+        default: Option<String>,
+    },
+    // 数组操作，作用于数组值；其他转换作用于数组时逐元素处理
+    Foreach { transformations: Vec<Transformation> },
+    Filter {
+        regex: Pattern,
+        #[serde(default)]
+        invert: bool,
+    },
+    Join { separator: String },
+    First,
+    Last,
+    Length,
+    // 脚本转换，输入为 value，返回值为结果
+    Script(ScriptConfig),
+    // wasm 插件转换
+    Plugin(PluginConfig),
+}
+
+// rhai 脚本，source 与 file 二选一
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ScriptConfig {
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_script_timeout_ms() -> u64 {
+    100
+}
+
+// wasm 插件，fuel 限制执行指令数
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PluginConfig {
+    pub file: String,
+    #[serde(default = "default_plugin_function")]
+    pub function: String,
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+}
+
+fn default_plugin_function() -> String {
+    "transform".to_string()
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+// 加载配置时编译的正则，无效的正则使配置加载失败
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Pattern(pub regex::Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        regex::Regex::new(&pattern).map(Pattern)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LookupMode {
+    #[default]
+    Exact,
+    Prefix,
+    Contains,
+    Regex,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -95,6 +993,7 @@ pub enum MixSource {
     Header(String),
     BodyField(String),
     Query(String),
+    Var(String),
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -103,6 +1002,7 @@ pub enum MixTarget {
     Header(String),
     BodyField(String),
     Query(String),
+    Var(String),
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
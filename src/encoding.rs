@@ -0,0 +1,166 @@
+use flate2::{
+    read::{DeflateDecoder, GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use hyper::{header, header::HeaderValue, HeaderMap};
+use std::io::{Read, Write};
+
+use crate::BodyReadError;
+
+// 需要读取上游 body 时请求的编码，均可解码
+pub const SUPPORTED: &str = "gzip, deflate, br";
+
+/// body 内容编码
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    fn parse(name: &str) -> Option<Encoding> {
+        match name.trim().to_lowercase().as_str() {
+            "" | "identity" => Some(Encoding::Identity),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// 读取 Content-Encoding，不支持的编码返回 Err
+    pub fn from_headers(headers: &HeaderMap) -> Result<Encoding, String> {
+        match headers
+            .get(header::CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap_or_default())
+        {
+            None => Ok(Encoding::Identity),
+            Some(name) => Encoding::parse(name).ok_or_else(|| name.to_string()),
+        }
+    }
+}
+
+/// 按 Accept-Encoding 的 q 值选择编码，同权重时优先 br、gzip、deflate
+pub fn negotiate(accept: Option<&HeaderValue>) -> Encoding {
+    let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+        return Encoding::Identity;
+    };
+    let mut best = (Encoding::Identity, 0.0);
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let Some(encoding) = Encoding::parse(name) else {
+            continue;
+        };
+        let rank = |e: Encoding| match e {
+            Encoding::Brotli => 3,
+            Encoding::Gzip => 2,
+            Encoding::Deflate => 1,
+            Encoding::Identity => 0,
+        };
+        if q > 0.0 && (q > best.1 || (q == best.1 && rank(encoding) > rank(best.0))) {
+            best = (encoding, q);
+        }
+    }
+    best.0
+}
+
+// 解码输出限制在 limit 字节内，防止压缩炸弹
+fn read_limited<R: Read>(reader: R, limit: usize) -> Result<Vec<u8>, BodyReadError> {
+    let mut out = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| BodyReadError::Read(e.to_string()))?;
+    if out.len() > limit {
+        return Err(BodyReadError::TooLarge);
+    }
+    Ok(out)
+}
+
+/// 解码 body，deflate 兼容 zlib 与 raw deflate
+pub fn decode(body: &[u8], encoding: Encoding, limit: usize) -> Result<Vec<u8>, BodyReadError> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => read_limited(GzDecoder::new(body), limit),
+        Encoding::Deflate => match read_limited(ZlibDecoder::new(body), limit) {
+            Err(BodyReadError::Read(_)) => read_limited(DeflateDecoder::new(body), limit),
+            decoded => decoded,
+        },
+        Encoding::Brotli => read_limited(brotli::Decompressor::new(body, 4096), limit),
+    }
+}
+
+/// 编码 body
+pub fn encode(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                encoder.write_all(body)?;
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_by_quality() {
+        let accept = |s: &'static str| HeaderValue::from_static(s);
+        assert_eq!(negotiate(None), Encoding::Identity);
+        assert_eq!(
+            negotiate(Some(&accept("gzip, deflate, br"))),
+            Encoding::Brotli
+        );
+        assert_eq!(
+            negotiate(Some(&accept("br;q=0.5, gzip;q=0.8"))),
+            Encoding::Gzip
+        );
+        assert_eq!(negotiate(Some(&accept("gzip;q=0, zstd"))), Encoding::Identity);
+    }
+
+    #[test]
+    fn round_trip_and_limit() {
+        let body = br#"{"answer":"hello hello hello hello"}"#;
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let encoded = encode(body, encoding).unwrap();
+            assert_eq!(decode(&encoded, encoding, 1024).ok().unwrap(), body.to_vec());
+            assert!(matches!(
+                decode(&encoded, encoding, 8),
+                Err(BodyReadError::TooLarge)
+            ));
+        }
+    }
+}
@@ -1,6 +1,8 @@
 #![allow(dead_code, unused_imports)]
 use axum::{
     body::{Bytes, Body},
+    extract::ws::{rejection::WebSocketUpgradeRejection, WebSocketUpgrade},
+    extract::ConnectInfo,
     http::{header, Method, StatusCode, Uri},
     response::sse::{Event, KeepAlive, Sse},
     response::IntoResponse,
@@ -22,17 +24,40 @@ use tokio::task::yield_now;
 use tracing::{event, Level};
 use url::form_urlencoded;
 
+mod admin;
+mod auth;
+mod breaker;
+mod cache;
 mod config;
+mod encoding;
+mod mapping;
+mod oauth2;
+mod oidc;
+mod openai;
+mod plugin;
+mod pool;
+mod retry;
+mod metrics;
+mod ratelimit;
+mod script;
+mod session;
+mod signing;
+mod sse;
+mod transform;
+mod upstream;
+mod ws;
 use crate::config::{
-    AppConfig, BodyConversion, MethodMapping, MixAction, MixSource, MixTarget, PathConfig,
-    ServiceType,
+    AppConfig, BodyConversion, CircuitBreakerConfig, EjectionConfig, GlobalConfig, MethodMapping,
+    MixAction, MixSource, MixTarget, PathConfig, RetryConfig, ServiceType, StaticResponse,
+    UpstreamAuthConfig,
 };
+use crate::encoding::Encoding;
+use crate::mapping::{MappingParts, StageError};
 use ::config::{Config, Environment};
-use async_stream;
 use regex::Regex;
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
-async fn load_config() -> anyhow::Result<(AppConfig, HashMap<String, PathConfig>)> {
+async fn load_config() -> anyhow::Result<(AppConfig, HashMap<String, PathConfig>, GlobalConfig)> {
     let _ = dotenv::dotenv().ok(); // 预加载 .env
 
     event!(Level::INFO, "Loading config");
@@ -66,11 +91,8 @@ async fn load_config() -> anyhow::Result<(AppConfig, HashMap<String, PathConfig>
     } // 根据 use_mode 加载不同的配置逻辑
 
     let config_content = std::fs::read_to_string(&app_config.config_path)?;
-    let path_configs: HashMap<String, PathConfig> = serde_yaml::from_str(&config_content)
-        .map_err(|e| {
-            event!(Level::ERROR, "Failed to parse config file: {}", e);
-        })
-        .unwrap();
+    let (path_configs, global_config) = crate::config::parse_mapping(&config_content)
+        .inspect_err(|e| event!(Level::ERROR, "Failed to parse config file: {}", e))?;
 
     event!(
         Level::DEBUG,
@@ -78,7 +100,7 @@ async fn load_config() -> anyhow::Result<(AppConfig, HashMap<String, PathConfig>
         path_configs
     );
 
-    Ok((app_config, path_configs))
+    Ok((app_config, path_configs, global_config))
 }
 
 fn json_to_flat_map(value: &Value, prefix: &str, result: &mut HashMap<String, Value>) {
@@ -193,6 +215,43 @@ fn insert_recursive(current: &mut Value, parts: &[&str], value: Value) {
     }
 }
 
+// 映射后变化的扁平 key 写回原 json，未变化的部分保持原样
+fn apply_flat_changes(
+    root: &mut Value,
+    before: &HashMap<String, Value>,
+    after: &HashMap<String, Value>,
+) {
+    for key in before.keys().filter(|k| !after.contains_key(*k)) {
+        remove_recursive(root, &parse_key_path(key));
+    }
+    for (key, value) in after {
+        if before.get(key) != Some(value) {
+            insert_recursive(root, &parse_key_path(key), value.clone());
+        }
+    }
+}
+
+fn remove_recursive(current: &mut Value, parts: &[&str]) {
+    let Some((first, rest)) = parts.split_first() else {
+        return;
+    };
+    let child = match current {
+        Value::Object(map) if rest.is_empty() => {
+            map.remove(*first);
+            return;
+        }
+        Value::Object(map) => map.get_mut(*first),
+        Value::Array(arr) => first.parse::<usize>().ok().and_then(|i| arr.get_mut(i)),
+        _ => None,
+    };
+    match child {
+        // 数组元素置空，保持其余元素的下标
+        Some(child) if rest.is_empty() => *child = Value::Null,
+        Some(child) => remove_recursive(child, rest),
+        None => {}
+    }
+}
+
 /// 递归处理数组标识
 fn post_process_arrays(map: &mut Map<String, Value>) {
     if let Some(Value::Array(arr)) = map.remove("_array") {
@@ -248,29 +307,6 @@ pub fn multimap_to_query(multimap: &HashMap<String, Vec<String>>) -> String {
         )
         .finish()
 }
-// json body 多级转换
-fn merge_subfields(
-    map: &HashMap<String, Value>,
-    parent_key: &str,
-    pairs: &mut HashMap<String, Value>,
-) {
-    // 处理父键自身的值（单层结构）
-    if let Some(value) = map.get(parent_key) {
-        pairs.insert(parent_key.to_string(), value.clone());
-    }
-
-    if !pairs.is_empty() {
-        return;
-    }
-
-    // 处理嵌套子字段（多层结构）
-    let prefix = format!("{}.", parent_key);
-    for (k, v) in map {
-        if let Some(sub_key) = k.strip_prefix(&prefix) {
-            pairs.insert(sub_key.to_string(), v.clone());
-        }
-    }
-}
 // json body 多级转换为字符串
 fn json_body_to_string(
     map: &HashMap<String, Value>,
@@ -278,14 +314,15 @@ fn json_body_to_string(
 ) -> String {
     let mut pairs = Vec::new();
 
+    let value_str = |v: &Value| match v {
+        Value::String(s) => s.clone(),
+        _ => v.to_string(),
+    };
     if map.len() == 1 {
-        return map.values().take(1).nth(0).unwrap().as_str().unwrap().to_string();
+        return map.values().next().map(value_str).unwrap_or_default();
     }
     for (k, v) in map {
-        let value_str = match v {
-            Value::String(s) => s.as_str().to_string(),
-            _ => v.to_string(),
-        };
+        let value_str = value_str(v);
         let formatted = format
             .replace("{key}", k)
             .replace("{value}", &value_str);
@@ -334,134 +371,259 @@ fn map_to_form_body(
     ))
 }
 
-// 处理转换
-fn apply_transformations(transformations: &[Transformation], value: &str, dst_value: Option<&str>) -> Option<String> {
-    let mut result = value.to_string();
-
-    for transform in transformations {
-        match transform {
-            Transformation::Base64Decode => {
-                result = base64::prelude::BASE64_STANDARD
-                    .decode(&result)
-                    .ok()
-                    .and_then(|bytes| String::from_utf8(bytes).ok())
-                    .unwrap_or_default();
-            }
-            Transformation::Base64Encode => {
-                result = base64::prelude::BASE64_STANDARD
-                    .encode(&result);
-            }
-            Transformation::Split { separator, index } => {
-                result = result
-                    .split(separator)
-                    .nth(*index)
-                    .unwrap_or_default()
-                    .to_string();
-            }
-            Transformation::Replace { from, to } => {
-                result = result.replace(from, to);
-            }
-            Transformation::Format { format } => {
-                result = format!("{}{}", format, result);
-            }
-            Transformation::Append { value } => {
-                result.push_str(value);
-            }
-            Transformation::Merge => {
-                if let Some(d_value) = dst_value {
-                    result.push_str(d_value);
-                }
-            }
-            Transformation::If => {
-                if let Some(d_val) = dst_value {
-                    if !d_val.is_empty() {
-                        result = d_val.to_string();
-                    }
-                }
-            }
-            // Test
-            Transformation::Extract { regex } => {
-                let re = Regex::new(regex).unwrap();
-                result = re.find(&result)
-                .map(|mat| mat.as_str().to_string())
-                .unwrap_or_else(|| result);
-            }
-            Transformation::Lowercase => {
-                result = result.to_lowercase();
-            }
-            Transformation::Uppercase => {
-                result = result.to_uppercase();
-            }
-        }
+// 转发用的 http client
+fn build_client() -> Client {
+    ClientBuilder::new()
+        .danger_accept_invalid_hostnames(true)
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
 
-        if result.is_empty() {
-            return None;
-        }
+// 按原方法构造上游请求，GET/HEAD 不带 body
+fn upstream_request(
+    client: &Client,
+    method: &Method,
+    url: &str,
+    body: reqwest::Body,
+) -> reqwest::RequestBuilder {
+    let builder = client.request(method.clone(), url);
+    if method == Method::GET || method == Method::HEAD {
+        builder
+    } else {
+        builder.body(body)
     }
+}
 
-    Some(result)
+/// 一次上游调用的重试配置、熔断许可、负载均衡选中的地址和上游 token 配置
+#[derive(Default)]
+pub struct UpstreamCall<'a> {
+    pub retry: Option<&'a RetryConfig>,
+    pub breaker: Option<(breaker::Guard, &'a CircuitBreakerConfig)>,
+    pub target: Option<(&'a pool::Selected, &'a EjectionConfig)>,
+    pub auth: Option<&'a UpstreamAuthConfig>,
 }
 
-// 重构，获取headervalue
-fn get_header_val(
-    headers_map: &mut hyper::HeaderMap,
-    action: &config::MixAction,
-    src: &String,
-) -> Option<HeaderValue> {
-    let value = match &action {
-        MixAction::Move => headers_map.remove(src.as_str()),
-        MixAction::Copy => headers_map.get(src.as_str()).cloned(),
-        MixAction::AddTarget(value) => Some(value.parse().unwrap()),
-        MixAction::DeleteSrc => {
-            headers_map.remove(src.as_str());
-            None
+// 发送上游请求：按配置重试，结果计入熔断和负载均衡，上游拒绝 token 时丢弃缓存
+pub async fn send_upstream(
+    call: UpstreamCall<'_>,
+    build: impl FnMut() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, (StatusCode, String)> {
+    let response = retry::send(call.retry, build).await;
+    if let Some((guard, conf)) = call.breaker {
+        guard.record(conf, breaker::is_failure(conf, &response));
+    }
+    if let Some((target, conf)) = call.target {
+        target.record(conf, &response);
+    }
+    let response = response.map_err(|e| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Forward request failed: {}", e),
+        )
+    })?;
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        if let Some(auth_conf) = call.auth {
+            upstream::invalidate(auth_conf);
         }
-    };
-    value
+    }
+    Ok(response)
 }
 
-// 重构，获取headervalue
-fn get_querymap_val(
-    map: &mut HashMap<String, Vec<String>>,
-    action: &config::MixAction,
-    src: &String,
-) -> Option<Vec<String>> {
-    let value = match &action {
-        MixAction::Move => map.remove(src.as_str()),
-        MixAction::Copy => map.get(src.as_str()).cloned(),
-        MixAction::AddTarget(value) => Some(vec![value.clone().parse().unwrap()]),
-        MixAction::DeleteSrc => {
-            map.remove(src.as_str());
-            None
-        }
-    };
-    value
+// 保存映射后的会话数据
+fn save_session(
+    conf: &config::SessionConfig,
+    session: &mut session::Session,
+    vars: &HashMap<String, Value>,
+) -> Result<(), (StatusCode, String)> {
+    session::save(conf, session, vars).map_err(|e| {
+        event!(Level::ERROR, "Session save failed: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Session save failed: {}", e),
+        )
+    })
 }
 
-// 重构，获取headervalue
-fn get_bodymap_val(
-    map: &mut HashMap<String, Value>,
-    action: &config::MixAction,
-    src: &String,
-) -> Option<Value> {
-    let value = match &action {
-        MixAction::Move => map.remove(src.as_str()),
-        MixAction::Copy => map.get(src.as_str()).cloned(),
-        MixAction::AddTarget(value) => Some(value.clone().parse().unwrap()),
-        MixAction::DeleteSrc => {
-            map.remove(src.as_str());
-            None
+// 固定响应
+fn static_response(conf: &StaticResponse, replacements: &[(&str, &str)]) -> Response {
+    let mut body = conf.body.clone();
+    for (k, v) in replacements {
+        body = body.replace(k, v);
+    }
+    let status = StatusCode::from_u16(conf.status).unwrap_or(StatusCode::BAD_REQUEST);
+    let content_type = conf
+        .content_type
+        .clone()
+        .unwrap_or_else(|| mime::TEXT_PLAIN_UTF_8.to_string());
+    (status, [(header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+// 读取 body 失败的原因
+pub enum BodyReadError {
+    TooLarge,
+    Read(String),
+}
+
+/// 读取 body，超过 limit 字节时停止读取
+async fn read_body_limited<S, E>(stream: S, limit: usize) -> Result<Bytes, BodyReadError>
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut buf = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| BodyReadError::Read(e.to_string()))?;
+        if buf.len() + chunk.len() > limit {
+            return Err(BodyReadError::TooLarge);
         }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
+// required 字段缺失的响应，未配置时返回 400
+fn missing_field_response(conf: Option<&StaticResponse>, field: &str) -> Response {
+    match conf {
+        Some(conf) => static_response(conf, &[("{field}", field)]),
+        None => (
+            StatusCode::BAD_REQUEST,
+            format!("Missing required field: {}", field),
+        )
+            .into_response(),
+    }
+}
+
+/// 根据使用模式匹配路径配置，返回 (配置, 目标地址, 转发路径)
+fn resolve_route(
+    app_config: &AppConfig,
+    path_configs: &HashMap<String, PathConfig>,
+    uri: &Uri,
+) -> Result<(Option<PathConfig>, String, String), (StatusCode, String)> {
+    let path = uri.path();
+    let sso_url = || {
+        app_config.sso_url.clone().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "SSO URL not configured".to_string(),
+        ))
     };
-    value
+    match app_config.use_mode {
+        // 代理模式，如果 config 为空， 则执行代理模式
+        UseMode::Proxy => match path_configs.get(path) {
+            // 命中配置
+            Some(config) => {
+                let (base_url, path) = match &config.request.target_service {
+                    ServiceType::Dify | ServiceType::OpenAi(_) => {
+                        (app_config.dify_url.clone(), path)
+                    }
+                    ServiceType::Redirect(Some(u)) => (u.clone(), ""),
+                    ServiceType::Redirect(None) | ServiceType::OAuth2Adapter(_) => {
+                        (sso_url()?, path)
+                    }
+                    // 使用原始请求的host
+                    ServiceType::SSO | ServiceType::SSE(_) => (
+                        uri.host()
+                            .ok_or((StatusCode::BAD_REQUEST, "Host header missing".to_string()))?
+                            .to_string(),
+                        path,
+                    ),
+                };
+                Ok((Some(config.clone()), base_url, path.to_string()))
+            }
+            // 未命中配置，判断是否为入栈请求，入栈请求则转发到 dify_url，否则转发到原始请求的host
+            None => {
+                let host = uri.host();
+                if host.is_some() && app_config.dify_host.as_deref() == host {
+                    // 入栈
+                    Ok((None, app_config.dify_url.clone(), path.to_string()))
+                } else {
+                    // 出站
+                    let host = host
+                        .ok_or((StatusCode::BAD_REQUEST, "Host header missing".to_string()))?;
+                    Ok((
+                        None,
+                        format!("{}://{}", uri.scheme_str().unwrap_or("https"), host),
+                        path.to_string(),
+                    ))
+                }
+            }
+        },
+        // 正常模式， config 不能为空，否则返回404
+        UseMode::Normal => {
+            let config = path_configs.get(path).ok_or((
+                StatusCode::NOT_FOUND,
+                format!("Path {} not configured", path),
+            ))?;
+            let (base_url, path) = match &config.request.target_service {
+                ServiceType::Dify | ServiceType::OpenAi(_) => (app_config.dify_url.clone(), path),
+                ServiceType::Redirect(Some(u)) => (u.clone(), ""),
+                ServiceType::Redirect(None)
+                | ServiceType::SSO
+                | ServiceType::SSE(_)
+                | ServiceType::OAuth2Adapter(_) => (sso_url()?, path),
+            };
+            Ok((Some(config.clone()), base_url, path.to_string()))
+        }
+    }
+}
+
+// 请求占用的上游资源
+#[derive(Default)]
+struct InFlight {
+    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    target: Option<pool::Selected>,
 }
 
 async fn proxy_handler(
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    uri: Uri,
+    method: Method,
+    headers: header::HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, String)> {
+    let mut session = session::Session::default();
+    let mut in_flight = InFlight::default();
+    let mut response = proxy_request(
+        upgrade,
+        client_addr,
+        uri,
+        method,
+        headers,
+        body,
+        &mut session,
+        &mut in_flight,
+    )
+    .await?;
+    // 新建或清空会话时写回 cookie
+    if let Some(cookie) = session.set_cookie {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    // 上游并发许可和选中的地址保持到响应 body 发送完成
+    if in_flight.permit.is_some() || in_flight.target.is_some() {
+        response = response.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let _ = &in_flight;
+                chunk
+            }))
+        });
+    }
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn proxy_request(
     //request: axum::extract::Request,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    client_addr: SocketAddr,
     uri: Uri,
     method: Method,
     headers: header::HeaderMap,
-    body: Bytes,
+    body: Body,
+    session: &mut session::Session,
+    in_flight: &mut InFlight,
 ) -> Result<Response, (StatusCode, String)> {
     // if method == Method::CONNECT {
     //     return handle_https_tunnel(uri, *addr).await;
@@ -474,7 +636,7 @@ async fn proxy_handler(
     // let headers: header::HeaderMap = request.headers().clone();
     // let body: Bytes = axum::body::to_bytes(request.into_body(),usize::MAX).await.unwrap();
 
-    let (app_config, path_configs) = load_config().await.map_err(|e| {
+    let (app_config, path_configs, global_config) = load_config().await.map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Config load failed: {}", e),
@@ -492,116 +654,257 @@ async fn proxy_handler(
         uri,
         headers
             .iter()
-            .map(|(n, v)| format!("{}={}", n, v.to_str().unwrap()))
+            .map(|(n, v)| format!("{}={}", n, v.to_str().unwrap_or_default()))
             .collect::<Vec<_>>(),
-        body.len()
+        headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
     );
 
-    let mut path = uri.path();
     let query = uri.query();
 
-    event!(Level::DEBUG, "Path: {:?}", path);
+    event!(Level::DEBUG, "Path: {:?}", uri.path());
     event!(Level::DEBUG, "Query: {:?}", query);
 
-    // 模式
-    let (config, base_url) = match use_mode {
-        // 代理模式，如果 config 为空， 则执行代理模式
-        UseMode::Proxy => {
-            let config = path_configs.get(path).clone();
-            match config {
-                // 命中配置
-                Some(config) => (
-                    Some(config.clone()),
-                    match config.request.target_service.clone() {
-                        ServiceType::Dify => &app_config.dify_url,
-                        ServiceType::Redirect(url) => {
-                            if let Some(u) = url {
-                                path = "";
-                                &u.clone()
-                            }
-                            else {
-                                &app_config
-                                .sso_url
-                                .ok_or((
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("SSO URL not configured"),
-                                ))?
-                                .clone()
-                            }
-                        }
-                        ServiceType::SSO | ServiceType::SSE(_) => &uri
-                            .host()
-                            .ok_or((StatusCode::BAD_REQUEST, format!("Host header missing")))?
-                            .to_string()
-                            .clone(), // 使用原始请求的host
-                    },
+    // OIDC discovery 文档和 JWKS
+    if let Some(oidc_conf) = &global_config.oidc {
+        if let Some(response) = oidc::handle(uri.path(), oidc_conf, &app_config, &path_configs) {
+            return Ok(response);
+        }
+    }
+
+    // Prometheus 指标
+    if let Some(response) = metrics::handle(uri.path(), global_config.metrics_path.as_ref()) {
+        return Ok(response);
+    }
+
+    // 上游状态管理接口
+    if let Some(response) = admin::handle(uri.path(), &global_config) {
+        return Ok(response);
+    }
+
+    let (config, base_url, path) = resolve_route(&app_config, &path_configs, &uri)?;
+    // 路由引用的命名上游，配置了 url 时替换目标地址
+    let upstream = match config.as_ref().and_then(|c| c.request.upstream.clone()) {
+        Some(name) => {
+            let conf = global_config.upstreams.get(&name).cloned().ok_or((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Upstream {} not configured", name),
+            ))?;
+            Some((name, conf))
+        }
+        None => None,
+    };
+    // 配置了多个地址时按负载均衡策略选择
+    if let Some((name, conf)) = &upstream {
+        let key = pool::hash_key(&conf.balance, &headers, client_addr.ip());
+        in_flight.target = pool::pick(name, conf, key.as_deref());
+    }
+    let base_url = match &in_flight.target {
+        Some(target) => target.url.clone(),
+        None => upstream
+            .as_ref()
+            .and_then(|(_, u)| u.url.clone())
+            .unwrap_or(base_url),
+    };
+    let (upstream_auth, upstream_signing) = upstream
+        .as_ref()
+        .map(|(_, u)| (u.auth.clone(), u.signing.clone()))
+        .unwrap_or_default();
+    let (base_url, path) = (base_url.as_str(), path.as_str());
+
+    // 单次请求内的变量
+    let mut vars = HashMap::new();
+
+    // 边缘认证，验证通过的声明写入变量
+    if let Some(jwt_conf) = config.as_ref().and_then(|c| c.request.jwt.as_ref()) {
+        match auth::verify_jwt(jwt_conf, &headers) {
+            Ok(claims) => auth::claims_to_vars(jwt_conf, &claims, &mut vars),
+            Err(e) => {
+                event!(Level::INFO, "JWT authentication failed: {:?}", e);
+                return Ok(e.into_response());
+            }
+        }
+    }
+
+    // API key / Basic 认证，凭据转发前移除，身份写入变量
+    let mut headers = headers;
+    let mut query_string = query.map(str::to_string);
+    if let Some(key_conf) = config.as_ref().and_then(|c| c.request.api_key.as_ref()) {
+        let mut query_map = query.map(query_to_multimap).unwrap_or_default();
+        let query_len = query_map.len();
+        match auth::verify_api_key(key_conf, uri.path(), &mut headers, &mut query_map) {
+            Ok(identity) => auth::identity_to_vars(key_conf, &identity, &mut vars),
+            Err(e) => {
+                event!(Level::INFO, "API key authentication failed: {:?}", e);
+                return Ok(e.into_basic_response(key_conf));
+            }
+        }
+        // 移除了 query 中的 key 时重新生成 query
+        if query_map.len() != query_len {
+            query_string = Some(multimap_to_query(&query_map)).filter(|q| !q.is_empty());
+        }
+    }
+    let query = query_string.as_deref();
+
+    // 服务端会话，数据写入变量
+    let session_conf = global_config.session.as_ref();
+    if let Some(conf) = session_conf {
+        *session = session::load(conf, &mut headers, &mut vars);
+    }
+
+    // 限流，key 可以使用认证和会话写入的变量
+    if let Some(limit) = config.as_ref().and_then(|c| c.request.rate_limit.as_ref()) {
+        let key = ratelimit::limit_key(
+            limit,
+            &MappingParts {
+                headers: &mut headers,
+                query: Some(&mut query.map(query_to_multimap).unwrap_or_default()),
+                body: &mut HashMap::new(),
+                vars: &mut vars,
+            },
+            client_addr.ip(),
+        );
+        if let Err(retry_after) = ratelimit::acquire(uri.path(), &key, limit) {
+            event!(
+                Level::WARN,
+                "Rate limit exceeded on {} for {}, retry after {:?}",
+                uri.path(),
+                key,
+                retry_after
+            );
+            return Ok(ratelimit::too_many_requests(retry_after));
+        }
+    }
+
+    // websocket 升级请求，握手时注入上游 token
+    if let Ok(upgrade) = upgrade {
+        if let Some(auth_conf) = &upstream_auth {
+            upstream::apply_auth(auth_conf, &build_client(), &mut headers).await?;
+        }
+        return ws::proxy(upgrade, config, base_url, path, query, &headers, vars).await;
+    }
+
+    // 可重试的请求
+    let retry_conf = config
+        .as_ref()
+        .and_then(|c| c.request.retry.clone())
+        .filter(|r| r.allows(&get_method(&config, &method)));
+
+    // 需要处理 body、对 body 签名或重试时按上限读入内存，否则直接流式转发
+    let needs_req_body = config.as_ref().is_some_and(|c| c.request.needs_body());
+    let buffer_req_body = needs_req_body
+        || upstream_signing.as_ref().is_some_and(|s| s.needs_body())
+        || retry_conf.is_some();
+    let max_body_size = config
+        .as_ref()
+        .and_then(|c| c.request.max_body_size)
+        .unwrap_or(app_config.max_body_size);
+    let (body, stream_body) = if buffer_req_body {
+        let body = read_body_limited(body.into_data_stream(), max_body_size)
+            .await
+            .map_err(|e| match e {
+                BodyReadError::TooLarge => (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Request body exceeds {} bytes", max_body_size),
                 ),
-                // 未命中配置，判断是否为入栈请求，入栈请求则转发到 dify_url，否则转发到原始请求的host
-                None => {
-                    if uri.host().is_some()
-                        && app_config.dify_host.is_some()
-                        && app_config
-                            .dify_host
-                            .unwrap()
-                            .eq(&uri.host().unwrap().to_string())
-                    {
-                        // 入栈
-                        (None, &app_config.dify_url)
-                    } else {
-                        // 出站
-                        (
-                            None,
-                            &format!(
-                                "{}://{}",
-                                uri.scheme_str().unwrap_or("https"),
-                                uri.host().unwrap().to_string()
-                            ),
-                        )
-                    }
+                BodyReadError::Read(e) => {
+                    (StatusCode::BAD_REQUEST, format!("Body read failed: {}", e))
                 }
+            })?;
+        (body, None)
+    } else {
+        (Bytes::new(), Some(body))
+    };
+
+    // 压缩的请求 body 解码后再映射，以未压缩形式转发
+    let body = if needs_req_body {
+        let req_encoding = Encoding::from_headers(&headers).map_err(|e| {
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported Content-Encoding: {}", e),
+            )
+        })?;
+        headers.remove(header::CONTENT_ENCODING);
+        Bytes::from(
+            encoding::decode(&body, req_encoding, max_body_size).map_err(|e| match e {
+                BodyReadError::TooLarge => (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Request body exceeds {} bytes", max_body_size),
+                ),
+                BodyReadError::Read(e) => {
+                    (StatusCode::BAD_REQUEST, format!("Body decode failed: {}", e))
+                }
+            })?,
+        )
+    } else {
+        body
+    };
+    // 熔断打开时直接返回 fallback，不再等待上游
+    let breaker_conf = upstream.as_ref().and_then(|(_, u)| u.circuit_breaker.as_ref());
+    let breaker_guard = match (&upstream, breaker_conf) {
+        (Some((name, _)), Some(conf)) => match breaker::acquire(name, conf) {
+            Ok(guard) => Some(guard),
+            Err(open) => {
+                event!(Level::WARN, "Circuit breaker for upstream {} is open", name);
+                return Ok(breaker::fallback_response(conf, &open));
+            }
+        },
+        _ => None,
+    };
+
+    // 上游并发已满时排队，队列已满或超时返回 503
+    if let Some((name, conf)) = &upstream {
+        match upstream::acquire_permit(name, conf).await {
+            Ok(acquired) => in_flight.permit = acquired,
+            Err(upstream::Overloaded) => {
+                event!(Level::WARN, "Upstream {} overloaded, shed request", name);
+                return Ok(upstream::overloaded_response());
             }
-            // 返回结果
         }
-        // 正常模式， config 不能为空，否则返回404
-        UseMode::Normal => {
-            let config = path_configs
-                .get(path)
-                .ok_or((
-                    StatusCode::NOT_FOUND,
-                    format!("Path {} not configured", path),
-                ))?
-                .clone();
-            // 返回结果
-            (
-                Some(config.clone()),
-                match config.request.target_service {
-                    ServiceType::Dify => &app_config.dify_url,
-                    ServiceType::Redirect(url) => {
-                        if let Some(u) = url {
-                            path = "";
-                            &u.clone()
-                        }
-                        else {
-                            &app_config
-                            .sso_url
-                            .ok_or((
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("SSO URL not configured"),
-                            ))?
-                            .clone()
-                        }
-                    }
-                    ServiceType::SSO | ServiceType::SSE(_) => &app_config
-                        .sso_url
-                        .ok_or((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("SSO URL not configured"),
-                        ))?
-                        .clone(),
-                },
+    }
+
+    // OAuth2 / OIDC 端点适配
+    if let Some(route) = &config {
+        if let ServiceType::OAuth2Adapter(oauth2_conf) = &route.request.target_service {
+            // 配置了 upstream_url 时不经过负载均衡选中的地址
+            let target = in_flight
+                .target
+                .as_ref()
+                .filter(|_| oauth2_conf.upstream_url.is_none())
+                .zip(upstream.as_ref().map(|(_, u)| &u.ejection));
+            let call = UpstreamCall {
+                retry: route.request.retry.as_ref(),
+                breaker: breaker_guard.zip(breaker_conf),
+                target,
+                ..Default::default()
+            };
+            let response = oauth2::handle(
+                oauth2_conf,
+                route,
+                build_client(),
+                call,
+                base_url,
+                path,
+                &method,
+                &headers,
+                query,
+                &body,
+                &mut vars,
             )
+            .await?;
+            if let Some(conf) = session_conf {
+                save_session(conf, session, &vars)?;
+            }
+            return Ok(response);
         }
-    };
+    }
+
+    // 客户端可接受的响应编码
+    let client_accept_encoding = headers.get(header::ACCEPT_ENCODING).cloned();
+    // 需要读取响应 body 时才解码上游响应
+    let res_needs_body = config.as_ref().is_some_and(|c| c.response.needs_body());
 
     event!(Level::DEBUG, "matched config: {:?}", &config);
 
@@ -610,18 +913,20 @@ async fn proxy_handler(
 
     // query
     let mut query_map = match query {
-        Some(query) => query_to_multimap(&query),
+        Some(query) => query_to_multimap(query),
         None => HashMap::new(),
     };
     // body
     let mut json_map = HashMap::new();
+    // OpenAI 适配转发解析后的原始 json，避免扁平化丢失空数组/对象和特殊字符的 key
+    let mut json_body = None;
     // headers
     let mut headers_map = HeaderMap::new();
 
     let content_type = match method {
-        Method::POST | Method::PUT => headers.get(header::CONTENT_TYPE).cloned().ok_or((
+        Method::POST | Method::PUT if needs_req_body => headers.get(header::CONTENT_TYPE).cloned().ok_or((
             StatusCode::BAD_REQUEST,
-            format!("Missing Header: content-type"),
+            "Missing Header: content-type".to_string(),
         ))?,
         _ => HeaderValue::from_str("").unwrap(),
     };
@@ -632,6 +937,7 @@ async fn proxy_handler(
         let json_data: Value = serde_json::from_slice(&body)
             .map_err(|e| (StatusCode::BAD_REQUEST, format!("JSON parse error: {}", e)))?;
         json_to_flat_map(&json_data, "", &mut json_map);
+        json_body = Some(json_data);
     } else if content_type == mime::APPLICATION_WWW_FORM_URLENCODED.essence_str() {
         // form
         let form_data = serde_urlencoded::from_bytes::<HashMap<String, Value>>(&body)
@@ -642,226 +948,61 @@ async fn proxy_handler(
     // 未匹配的，添加源header到新header
     headers_map.extend(headers.clone());
 
+    let flat_before = match &config {
+        Some(conf) if matches!(conf.request.target_service, ServiceType::OpenAi(_)) => {
+            Some(json_map.clone())
+        }
+        _ => None,
+    };
+
     // 处理request.mix_mappings
     if let Some(conf) = &config {
-        for mapping in &conf.request.mix_mappings {
-            let m = mapping.clone();
-            let t = m.target.clone();
-            let trans_s = m.transformations.clone();
-            match (&m.source, t) {
-                // Header to Header
-                (MixSource::Header(src), MixTarget::Header(dst)) => {
-                    if let Some(mut value) = get_header_val(&mut headers_map, &m.action, src) {
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_header_val(&mut headers_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.to_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.to_str().unwrap(), dst_val.as_deref())
-                            {
-                                value = transformed.parse().unwrap();
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        headers_map.insert(obj.as_str(), value.clone());
-                    }
-                }
-                // Header to Body
-                (MixSource::Header(src), MixTarget::BodyField(dst)) => {
-                    if let Some(mut value) = get_header_val(&mut headers_map, &m.action, src) {
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_bodymap_val(&mut json_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.as_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans,&value.to_str().unwrap(), dst_val.as_deref())
-                            {
-                                value = transformed.parse().unwrap();
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        json_map.insert(
-                            obj.to_string(),
-                            Value::String(value.clone().to_str().unwrap().to_string()),
-                        );
-                    }
-                }
-                // Header to Query
-                (MixSource::Header(src), MixTarget::Query(dst)) => {
-                    if let Some(mut value) = get_header_val(&mut headers_map, &m.action, src) {
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_querymap_val(&mut query_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.join(",")));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.to_str().unwrap(), dst_val.as_deref())
-                            {
-                                value = transformed.parse().unwrap();
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        query_map.insert(
-                            obj.to_string(),
-                            vec![value.clone().to_str().unwrap().to_string()],
-                        );
-                    }
-                }
-                // Quert to Query
-                (MixSource::Query(src), MixTarget::Query(dst)) => {
-                    if let Some(mut value) = get_querymap_val(&mut query_map, &m.action, src){
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_querymap_val(&mut query_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.join(",")));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.join(",").as_str(),dst_val.as_deref())
-                            {
-                                let v:String = transformed.parse().unwrap();
-                                value = vec!(v.split(",").collect());
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        query_map.insert(obj.to_string(), value);
-                    }
-                }
-                // Query to Header
-                (MixSource::Query(src), MixTarget::Header(dst)) => {
-                    if let Some(mut value) = get_querymap_val(&mut query_map, &m.action, src){
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_header_val(&mut headers_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.to_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.join(",").as_str(), dst_val.as_deref())
-                            {
-                                let v:String = transformed.parse().unwrap();
-                                value = vec!(v.split(",").collect());
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        headers_map.insert(
-                            obj.as_str(),
-                            HeaderValue::from_str(value.join(",").as_str()).unwrap(),
-                        );
-                    }
-                }
-                // Query to Body
-                (MixSource::Query(src), MixTarget::BodyField(dst)) => {
-                    if let Some(mut value) = get_querymap_val(&mut query_map, &m.action, src){
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_bodymap_val(&mut json_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.as_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.join(",").as_str(),dst_val.as_deref())
-                            {
-                                let v:String = transformed.parse().unwrap();
-                                value = vec!(v.split(",").collect());
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        json_map.insert(obj.to_string(), Value::String(value.join(",")));
-                    }
-                }
-                // Body to Body
-                // TODO Handle transformations
-                (MixSource::BodyField(src), MixTarget::BodyField(dst)) => {
-                    let mut res_json = HashMap::<String, Value>::new();
-                    merge_subfields(&json_map, &src, &mut res_json);
-                    match &m.action {
-                        MixAction::Move => {
-                            for (k, v) in res_json.iter() {
-                                let src_key = format!("{}.{}",src,k);
-                                json_map.remove(src_key.as_str()); // delete source
-                                json_map.remove(k.as_str()); // delete source
-                                json_map.insert(k.clone().replace(src, dst.as_str()), v.clone());
-                            }
-                        }
-                        MixAction::Copy => {
-                            for (k, v) in res_json.iter() {
-                                json_map.insert(k.clone().replace(src, dst.as_str()), v.clone());
-                            }
-                        }
-                        MixAction::AddTarget(v) => {
-                            json_map.insert(src.clone(), Value::String(v.clone()));
-                        }
-                        MixAction::DeleteSrc => {
-                            for (k, _) in res_json.iter() {
-                                json_map.remove(k.as_str()); // delete source
-                            }
-                        }
-                    };
-                }
-                // Body to Query
-                // TODO Handle transformations
-                (MixSource::BodyField(src), MixTarget::Query(dst)) => {
-                    let mut res_json = HashMap::<String, Value>::new();
-                    merge_subfields(&json_map, &src, &mut res_json);
-                    let value = match &m.action {
-                        MixAction::Move => {
-                            for (k, _) in res_json.iter() {
-                                let src_key = format!("{}.{}",src,k);
-                                json_map.remove(src_key.as_str()); // delete source
-                                json_map.remove(k.as_str());
-                            }
-                            Some(json_body_to_string(&res_json, "{key}={value}"))
-                        }
-                        MixAction::Copy => Some(json_body_to_string(&res_json, "{key}={value}")),
-                        MixAction::AddTarget(v) => Some(v.clone()), // Add a static value to the query
-                        MixAction::DeleteSrc => {
-                            for (k, _) in res_json.iter() {
-                                json_map.remove(k.as_str());
-                            }
-                            None
-                        }
-                    };
-                    if let Some(value) = value {
-                        let obj = Box::leak(Box::new(dst));
-                        query_map.insert(obj.to_string(), vec![value]);
-                    }
-                }
-                // Body to Header
-                // TODO Handle transformations
-                (MixSource::BodyField(src), MixTarget::Header(dst)) => {
-                    let mut res_json = HashMap::<String, Value>::new();
-                    merge_subfields(&json_map, &src, &mut res_json);
-                    let value = match &m.action {
-                        MixAction::Move => {
-                            for (k, _) in res_json.iter() {
-                                let src_key = format!("{}.{}",src,k);
-                                json_map.remove(src_key.as_str()); // delete source
-                                json_map.remove(k.as_str());
-                            }
-                            Some(json_body_to_string(&res_json, "{key}={value}"))
-                        }
-                        MixAction::Copy => Some(json_body_to_string(&res_json, "{key}={value}")),
-                        MixAction::AddTarget(v) => Some(v.clone()), // Add a static value to the query
-                        MixAction::DeleteSrc => {
-                            for (k, _) in res_json.iter() {
-                                json_map.remove(k.as_str());
-                            }
-                            None
-                        }
-                    };
-                    if let Some(value) = value {
-                        let obj = Box::leak(Box::new(dst));
-                        headers_map
-                            .insert(obj.as_str(), HeaderValue::from_str(value.as_str()).unwrap());
-                    }
-                }
+        let staged = mapping::run_stage(
+            &conf.request.mix_mappings,
+            conf.request.script.as_ref(),
+            conf.request.plugin.as_ref(),
+            "request",
+            &mut MappingParts {
+                headers: &mut headers_map,
+                query: Some(&mut query_map),
+                body: &mut json_map,
+                vars: &mut vars,
+            },
+        );
+        match staged {
+            Ok(()) => {}
+            // required 字段缺失，直接拒绝请求
+            Err(StageError::MissingField(field)) => {
+                return Ok(missing_field_response(
+                    conf.request.missing_response.as_ref(),
+                    &field,
+                ));
+            }
+            Err(e) => {
+                event!(Level::ERROR, "Request stage failed: {}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
             }
         }
     }
+    if let Some(conf) = session_conf {
+        save_session(conf, session, &vars)?;
+    }
 
     event!(Level::DEBUG, "final body : {:?}", json_map);
 
     // 目标地址处理 + query参数
-    let target_url = if query_map.len() > 0 {
+    let target_url = if !query_map.is_empty() {
         format!("{}{}?{}", base_url, path, multimap_to_query(&query_map))
     } else {
         format!("{}{}", base_url, path)
     };
     event!(Level::DEBUG, "Target URL: {}", target_url);
 
-    let c = config.clone();
+    let target_service = config.as_ref().map(|c| c.request.target_service.clone());
 
     // redirect处理
-    let req_red = match c.clone().unwrap().request.target_service.clone() {
-        ServiceType::Redirect(_) => {
+    let req_red = match &target_service {
+        Some(ServiceType::Redirect(_)) => {
             // 处理重定向服务的请求
             let mut h = header::HeaderMap::new();
             h.insert(
@@ -875,8 +1016,41 @@ async fn proxy_handler(
         _ => None,
     };
 
-    if req_red.is_some() {
-        return Ok(req_red.unwrap().into_response());
+    if let Some(req_red) = req_red {
+        return Ok(req_red.into_response());
+    }
+
+    // 映射完成后注入上游 token
+    let client = build_client();
+    if let Some(auth_conf) = &upstream_auth {
+        upstream::apply_auth(auth_conf, &client, &mut headers_map).await?;
+    }
+
+    // OpenAI 协议适配，与普通转发一样经过重试、熔断和负载均衡
+    if let Some(ServiceType::OpenAi(openai_conf)) = &target_service {
+        let sse_conf = config.as_ref().map(|c| c.response.sse.clone()).unwrap_or_default();
+        let req = match (json_body, flat_before) {
+            (Some(mut json_body), Some(before)) => {
+                apply_flat_changes(&mut json_body, &before, &json_map);
+                json_body
+            }
+            _ => flat_map_to_json(&json_map),
+        };
+        let prepared = match openai::prepare(openai_conf, &headers_map, req) {
+            Ok(prepared) => prepared,
+            Err(message) => return Ok(openai::invalid_request(&message)),
+        };
+        let call = UpstreamCall {
+            retry: retry_conf.as_ref(),
+            breaker: breaker_guard.zip(breaker_conf),
+            target: in_flight.target.as_ref().zip(upstream.as_ref().map(|(_, u)| &u.ejection)),
+            auth: upstream_auth.as_ref(),
+        };
+        let response = send_upstream(call, || {
+            prepared.build(openai_conf, &client, base_url, &headers_map)
+        })
+        .await?;
+        return openai::respond(prepared, &sse_conf, response).await;
     }
 
     let def_json_body = (
@@ -919,17 +1093,19 @@ async fn proxy_handler(
         }
     };
 
-    let mut _b = String::new();
-    _b = String::from_utf8(converted_body.clone()).unwrap();
-    event!(Level::DEBUG, "Request Body: {:?}", &_b);
+    event!(
+        Level::DEBUG,
+        "Request Body: {:?}",
+        String::from_utf8_lossy(&converted_body)
+    );
 
     // 转换body类型
-    if content_type.is_some() {
+    if let Some(content_type) = content_type {
         // 处理Body转换的header
         headers_map.remove(header::CONTENT_TYPE);
         headers_map.insert(
             header::CONTENT_TYPE,
-            content_type.unwrap().to_string().parse().unwrap(),
+            content_type.to_string().parse().unwrap(),
         );
     }
 
@@ -941,11 +1117,13 @@ async fn proxy_handler(
         headers_map.insert(header::HOST, to_host.parse().unwrap()); // 设置目标host
     }
 
-    // 默认更新
-    headers_map.insert(
-        header::CONTENT_LENGTH,
-        converted_body.len().to_string().parse().unwrap(),
-    );
+    // 默认更新，流式转发时保留原 content-length
+    if stream_body.is_none() {
+        headers_map.insert(
+            header::CONTENT_LENGTH,
+            converted_body.len().to_string().parse().unwrap(),
+        );
+    }
 
     if headers_map.contains_key(header::TRANSFER_ENCODING) {
         let transfer_encoding = headers_map.get(header::TRANSFER_ENCODING);
@@ -960,10 +1138,6 @@ async fn proxy_handler(
         }
     }
 
-    // 移除压缩编码头
-    if headers_map.contains_key(header::ACCEPT_ENCODING){
-        headers_map.remove(header::ACCEPT_ENCODING);
-    }
     event!(Level::DEBUG, "Request Headers: {:?}", headers_map);
 
     event!(
@@ -975,54 +1149,69 @@ async fn proxy_handler(
         &converted_body.len()
     );
 
-    // sse 处理 所有前置处理完成后
-    let is_sse_req = match c.unwrap().request.target_service.clone() {
-        ServiceType::SSE(source) => {
-            // 解析配置字符串（例如 "bodyfield-stream"）
-            let (src_type, src_value) = source.split_once('-').expect("Invalid SSE source format");
-            match src_type.to_lowercase().as_str() {
-                "bodyfield" => json_map.get(src_value).and_then(|v| v.as_bool()).unwrap(),
-                "header" => headers_map
-                    .get(src_value)
-                    .and_then(|hv| hv.to_str().ok())
-                    .and_then(|s| s.parse().ok()).unwrap(),
-                "query" => query_map
-                    .get(src_value)
-                    .map(|values| Value::String(values.join(",")))
-                    .and_then(|v| v.as_bool()).unwrap(),
-                _ => false,
-            }
-        }
-        _ => false,
-    };
-
-    event!(Level::DEBUG, "is_sse_req: {:?}", is_sse_req);
-
-    // 发送请求
-    let client = ClientBuilder::new()
-        .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(true)
-        .redirect(reqwest::redirect::Policy::none())
-        .no_gzip()
-        .build();
+    // sse 处理 所有前置处理完成后：Accept 头、stream 配置或旧版 !sse 配置
+    let stream_source = config.as_ref().and_then(|c| sse::stream_source(&c.request));
+    let wants_stream = sse::request_wants_stream(
+        headers_map.get(header::ACCEPT),
+        stream_source.as_ref(),
+        &MappingParts {
+            headers: &mut headers_map.clone(),
+            query: Some(&mut query_map.clone()),
+            body: &mut json_map.clone(),
+            vars: &mut vars.clone(),
+        },
+    );
 
+    event!(Level::DEBUG, "wants_stream: {:?}", wants_stream);
 
-    let request_builder = match target_method {
-        Method::GET => client.unwrap().get(&target_url.clone()),
-        Method::POST => client.unwrap().post(&target_url.clone()).body(converted_body),
-        _ => unreachable!(),
-    };
+    // 需要处理响应 body 时只请求可解码的编码，sse 按事件处理时不压缩
+    if res_needs_body {
+        let accept_encoding = if wants_stream { "identity" } else { encoding::SUPPORTED };
+        headers_map.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static(accept_encoding),
+        );
+    }
 
-    let response = request_builder
-        .headers(headers_map)
-        .send()
-        .await
+    // 签名覆盖最终的 url、header 和 body
+    if let Some(signing_conf) = &upstream_signing {
+        let signed_body = stream_body.is_none().then_some(converted_body.as_slice());
+        signing::sign(
+            signing_conf,
+            &target_method,
+            &target_url,
+            &mut headers_map,
+            signed_body,
+            std::time::SystemTime::now(),
+        )
         .map_err(|e| {
+            event!(Level::ERROR, "Request signing failed: {}", e);
             (
-                StatusCode::BAD_GATEWAY,
-                format!("Forward request failed: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Request signing failed: {}", e),
             )
         })?;
+    }
+
+    // 发送请求，重试时重放内存中的 body
+    let mut stream_body = stream_body.map(|b| reqwest::Body::wrap_stream(b.into_data_stream()));
+    let converted_body = Bytes::from(converted_body);
+    let build_request = || {
+        let req_body = match stream_body.take() {
+            Some(stream_body) => stream_body,
+            None => reqwest::Body::from(converted_body.clone()),
+        };
+        upstream_request(&client, &target_method, &target_url, req_body)
+            .headers(headers_map.clone())
+    };
+
+    let call = UpstreamCall {
+        retry: retry_conf.as_ref(),
+        breaker: breaker_guard.zip(breaker_conf),
+        target: in_flight.target.as_ref().zip(upstream.as_ref().map(|(_, u)| &u.ejection)),
+        auth: upstream_auth.as_ref(),
+    };
+    let response = send_upstream(call, build_request).await?;
 
     // redirect
     if response.status().is_redirection() {
@@ -1053,33 +1242,63 @@ async fn proxy_handler(
 
     event!(Level::DEBUG, "Response res_status: {:?}", res_status);
 
-    // sse 忽略 response 配置
-    if is_sse_req {
+    // 上游返回 event-stream 时按 sse 处理，上游未声明类型时参考请求是否期望流式
+    let is_sse_res = sse::is_sse_response(&res_header, res_status, wants_stream);
+    event!(Level::DEBUG, "is_sse_res: {:?}", is_sse_res);
+
+    // sse 按事件处理 response 配置
+    if is_sse_res {
         res_headers_map.insert(
             header::CONTENT_TYPE,
             HeaderValue::from_static("text/event-stream"),
         );
+        // 事件重新序列化后长度会变化
+        res_headers_map.remove(header::CONTENT_LENGTH);
+
+        let res_conf = config.as_ref().map(|c| c.response.clone());
+        // 压缩的事件流无法按事件处理，直接透传
+        let compressed = Encoding::from_headers(&res_header) != Ok(Encoding::Identity);
+        if compressed {
+            event!(Level::WARN, "Compressed SSE response, skip event mapping");
+        }
+        if let Some(res_conf) = res_conf.filter(|c| !compressed && sse::needs_event_mapping(c)) {
+            event!(Level::DEBUG, "SSE request, map response events");
+            mapping::apply_mix_mappings(
+                &mapping::header_mappings(&res_conf.mix_mappings),
+                &mut MappingParts {
+                    headers: &mut res_headers_map,
+                    query: None,
+                    body: &mut HashMap::new(),
+                    vars: &mut vars,
+                },
+            )
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Missing required response field: {}", e.0),
+                )
+            })?;
+            let stream = sse::mapped_stream(
+                response.bytes_stream(),
+                res_conf.clone(),
+                res_headers_map.clone(),
+                vars,
+            );
+            let stream = sse::keepalive(stream, &res_conf.sse);
+            return Ok((res_status, res_headers_map, Body::from_stream(stream)).into_response());
+        }
 
-        event!(Level::DEBUG, "SSE request, ignore response config");
-
-        let stream = async_stream::stream! {
-            let mut stream = response.bytes_stream();
-            while let Some(chunk) = stream.next().await {
-                let chunk = match chunk {
-                        Ok(c) => c,
-                        Err(e) => {
-                            let error_msg = format!("Error: {}", e);
-                            yield Ok::<Vec<u8>, std::io::Error>(error_msg.into_bytes()); 
-                            continue;
-                        }
-                    };
-                event!(Level::DEBUG, "SSE chunk: {:?}", chunk);
-
-                yield Ok(
-                    chunk.to_vec()
-                );
-            };
-        };
+        event!(Level::DEBUG, "SSE request, no event mapping");
+
+        let mut sse_conf = config.as_ref().map(|c| c.response.sse.clone()).unwrap_or_default();
+        // 压缩的字节流中插入心跳会破坏解压
+        if compressed {
+            sse_conf.keepalive_secs = 0;
+        }
+        let stream = sse::keepalive(
+            sse::passthrough_stream(response.bytes_stream(), compressed),
+            &sse_conf,
+        );
 
         return Ok((
             res_status,
@@ -1089,29 +1308,59 @@ async fn proxy_handler(
             .into_response());
     }
 
-    // 没有配置response mix_mappings，直接返回response
-    if config.clone().is_some() {
-        let config = config.as_ref().unwrap();
-        if config.response.mix_mappings.is_empty() {
-            event!(
-                Level::DEBUG,
-                "No need process mix, Return response directly"
-            );
-            return Ok((
-                res_status,
-                res_headers_map,
-                axum::body::Bytes::from(response.bytes().await.unwrap()),
+    // 无需处理 body 的响应，只执行 header 映射后直接流式转发
+    if !res_needs_body {
+        event!(Level::DEBUG, "No need process body, stream response directly");
+        if let Some(conf) = &config {
+            mapping::apply_mix_mappings(
+                &conf.response.mix_mappings,
+                &mut MappingParts {
+                    headers: &mut res_headers_map,
+                    query: None,
+                    body: &mut HashMap::new(),
+                    vars: &mut vars,
+                },
             )
-                .into_response());
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Missing required response field: {}", e.0),
+                )
+            })?;
         }
+        res_headers_map.remove(header::TRANSFER_ENCODING);
+        return Ok((
+            res_status,
+            res_headers_map,
+            Body::from_stream(response.bytes_stream()),
+        )
+            .into_response());
     }
 
-    let res_body = response.bytes().await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Body read failed: {}", e),
-        )
-    })?;
+    let res_body = read_body_limited(response.bytes_stream(), max_body_size)
+        .await
+        .map_err(|e| match e {
+            BodyReadError::TooLarge => (
+                StatusCode::BAD_GATEWAY,
+                format!("Response body exceeds {} bytes", max_body_size),
+            ),
+            BodyReadError::Read(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Body read failed: {}", e),
+            ),
+        })?;
+    let res_body = Encoding::from_headers(&res_header)
+        .map_err(|e| format!("unsupported Content-Encoding {}", e))
+        .and_then(|enc| {
+            encoding::decode(&res_body, enc, max_body_size).map_err(|e| match e {
+                BodyReadError::TooLarge => {
+                    format!("Response body exceeds {} bytes", max_body_size)
+                }
+                BodyReadError::Read(e) => e,
+            })
+        })
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Body decode failed: {}", e)))?;
+    res_headers_map.remove(header::CONTENT_ENCODING);
 
     event!(
         Level::INFO,
@@ -1125,16 +1374,18 @@ async fn proxy_handler(
         res_body.len()
     );
     
-    let mut _rb = String::new();
-    _rb = String::from_utf8(res_body.clone().to_vec()).unwrap();
-    event!(Level::DEBUG, "Response origin Body: {:?}", &_rb);
+    event!(
+        Level::DEBUG,
+        "Response origin Body: {:?}",
+        String::from_utf8_lossy(&res_body)
+    );
 
     // body
     let mut res_json_map = HashMap::new();
 
     let res_content_type = res_headers_map.get(header::CONTENT_TYPE).cloned().ok_or((
         StatusCode::BAD_REQUEST,
-        format!("Missing Header: content-type"),
+        "Missing Header: content-type".to_string(),
     ))?;
 
     // 根据 response content-type 解析 res_body 数据
@@ -1162,109 +1413,35 @@ async fn proxy_handler(
         res_json_map = form_data.clone();
     }
 
-    // 处理response.mix_mappings
+    // 处理response.claims 和 response.mix_mappings
     if let Some(conf) = &config {
-        for mapping in &conf.response.mix_mappings {
-            let m = mapping.clone();
-            let t = m.target.clone();
-            let trans_s = m.transformations.clone();
-            match (&m.source, t) {
-                // Header to Header
-                (MixSource::Header(src), MixTarget::Header(dst)) => {
-                    if let Some(mut value) = get_header_val(&mut res_headers_map, &m.action, src) {
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_header_val(&mut res_headers_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.to_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.to_str().unwrap(),dst_val.as_deref())
-                            {
-                                value = transformed.parse().unwrap();
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        res_headers_map.insert(obj.as_str(), value.clone());
-                    }
-                }
-                // Header to Body
-                (MixSource::Header(src), MixTarget::BodyField(dst)) => {
-                    if let Some(mut value) = get_header_val(&mut res_headers_map, &m.action, src){
-                        if let Some(trans) = trans_s.clone() {
-                            let dst_val: Option<String> = get_bodymap_val(&mut res_json_map, &MixAction::Copy, &dst)
-                                .map_or(None, |v| Some(v.as_str().unwrap().to_string()));
-                            if let Some(transformed) =
-                            apply_transformations(&trans, &value.to_str().unwrap(), dst_val.as_deref())
-                            {
-                                value = transformed.parse().unwrap();
-                            }
-                        }
-                        let obj = Box::leak(Box::new(dst));
-                        res_json_map.insert(
-                            obj.to_string(),
-                            Value::String(value.clone().to_str().unwrap().to_string()),
-                        );
-                    }
-                }
-                // Body to Body
-                (MixSource::BodyField(src), MixTarget::BodyField(dst)) => {
-                    let mut res_json = HashMap::<String, Value>::new();
-                    merge_subfields(&res_json_map, &src, &mut res_json);
-                    match &m.action {
-                        MixAction::Move => {
-                            for (k, v) in res_json.iter() {
-                                let src_key = format!("{}.{}",src,k);
-                                res_json_map.remove(src_key.as_str()); // delete source
-                                res_json_map
-                                    .insert(k.clone().replace(src, dst.as_str()), v.clone());
-                            }
-                        }
-                        MixAction::Copy => {
-                            for (k, v) in res_json.iter() {
-                                res_json_map
-                                    .insert(k.clone().replace(src, dst.as_str()), v.clone());
-                            }
-                        }
-                        MixAction::AddTarget(v) => {
-                            res_json_map.insert(src.clone(), Value::String(v.clone()));
-                        }
-                        MixAction::DeleteSrc => {
-                            for (k, _) in res_json.iter() {
-                                res_json_map.remove(k.as_str()); // delete source
-                            }
-                        }
-                    };
-                }
-                // Body to Header
-                (MixSource::BodyField(src), MixTarget::Header(dst)) => {
-                    let mut res_json = HashMap::<String, Value>::new();
-                    merge_subfields(&res_json_map, &src, &mut res_json);
-                    let value = match &m.action {
-                        MixAction::Move => {
-                            for (k, _) in res_json.iter() {
-                                let src_key = format!("{}.{}",src,k);
-                                res_json_map.remove(src_key.as_str()); // delete source
-                                res_json_map.remove(k.as_str());
-                            }
-                            Some(json_body_to_string(&res_json, "{key}={value}"))
-                        }
-                        MixAction::Copy => Some(json_body_to_string(&res_json, "{key}={value}")),
-                        MixAction::AddTarget(v) => Some(v.clone()), // Add a static value to the query
-                        MixAction::DeleteSrc => {
-                            for (k, _) in res_json.iter() {
-                                json_map.remove(k.as_str());
-                            }
-                            None
-                        }
-                    };
-                    if let Some(value) = value {
-                        let obj = Box::leak(Box::new(dst));
-                        res_headers_map
-                            .insert(obj.as_str(), HeaderValue::from_str(value.as_str()).unwrap());
-                    }
-                }
-                // 无其他配置
-                _ => {}
-            }
+        if let Some(claims) = &conf.response.claims {
+            mapping::apply_claims(claims, &mut res_json_map).map_err(|e| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Missing required claim: {}", e.0),
+                )
+            })?;
         }
+        mapping::run_stage(
+            &conf.response.mix_mappings,
+            conf.response.script.as_ref(),
+            conf.response.plugin.as_ref(),
+            "response",
+            &mut MappingParts {
+                headers: &mut res_headers_map,
+                query: None,
+                body: &mut res_json_map,
+                vars: &mut vars,
+            },
+        )
+        .map_err(|e| {
+            event!(Level::ERROR, "Response stage failed: {}", e);
+            (StatusCode::BAD_GATEWAY, e.to_string())
+        })?;
+    }
+    if let Some(conf) = session_conf {
+        save_session(conf, session, &vars)?;
     }
 
     let def_res_json_body = (
@@ -1298,15 +1475,31 @@ async fn proxy_handler(
         }
     };
 
-    if res_content_type.is_some() {
+    if let Some(res_content_type) = res_content_type {
         // 处理Body转换的header
         res_headers_map.remove(header::CONTENT_TYPE);
         res_headers_map.insert(
             header::CONTENT_TYPE,
-            res_content_type.unwrap().to_string().parse().unwrap(),
+            res_content_type.to_string().parse().unwrap(),
         );
     }
 
+    // 按客户端 Accept-Encoding 重新压缩
+    let res_encoding = encoding::negotiate(client_accept_encoding.as_ref());
+    let res_converted_body = encoding::encode(&res_converted_body, res_encoding).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Body encode failed: {}", e),
+        )
+    })?;
+    if res_encoding != Encoding::Identity {
+        res_headers_map.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(res_encoding.as_str()),
+        );
+        res_headers_map.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
     // 代理模式不需要处理host
     if use_mode == UseMode::Normal {
         let from_host = app_config.self_host.clone();
@@ -1328,9 +1521,7 @@ async fn proxy_handler(
 
             event!(Level::DEBUG, "Response status: {:?}", res_status);
             event!(Level::DEBUG, "Response headers: {:?}", res_headers_map);
-            let mut _rb = String::new();
-            _rb = String::from_utf8(res_converted_body.clone()).unwrap();
-            event!(Level::DEBUG, "Response Body: {:?}", &_rb);
+            event!(Level::DEBUG, "Response Body size: {}", res_converted_body.len());
 
             return Ok((
                 res_status,
@@ -1353,9 +1544,7 @@ async fn proxy_handler(
 
     event!(Level::DEBUG, "Response status: {:?}", status);
     event!(Level::DEBUG, "Response headers: {:?}", headers);
-    let mut _rb = String::new();
-    _rb = String::from_utf8(res_converted_body.clone()).unwrap();
-    event!(Level::DEBUG, "Response Body: {:?}", &_rb);
+    event!(Level::DEBUG, "Response Body size: {}", res_converted_body.len());
 
     event!(
         Level::INFO,
@@ -1386,9 +1575,12 @@ async fn main() {
     event!(Level::INFO, "Starting sso_adapter server on port 8080");
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener,app.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 
@@ -1404,11 +1596,70 @@ mod tests {
         let john = json!({"role":["aad", {"name":"aa", "sex":"n", "dd": ["ad"]}],"t1":{"ar": 123}});
         json_to_flat_map(&john, "",&mut res_json_map);
         for ele in res_json_map.clone() {
-            print!("{}\n",ele.0)
+            println!("{}", ele.0)
         }
         let v = flat_map_to_json(&res_json_map);
         println!("{:?}", v.clone());
-        println!("\n{}", v.to_string());
+        println!("\n{}", v);
         assert_eq!(1,1);
     }
+
+    #[tokio::test]
+    async fn read_body_stops_at_limit() {
+        let chunks = || {
+            futures_util::stream::iter(vec![
+                Ok::<Bytes, std::io::Error>(Bytes::from("hello ")),
+                Ok(Bytes::from("world")),
+            ])
+        };
+        let body = read_body_limited(chunks(), 11).await.ok().unwrap();
+        assert_eq!(body, Bytes::from("hello world"));
+        assert!(matches!(
+            read_body_limited(chunks(), 10).await,
+            Err(BodyReadError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn upstream_request_keeps_method_and_body() {
+        let client = Client::new();
+        for method in [Method::PUT, Method::DELETE, Method::PATCH] {
+            let request = upstream_request(&client, &method, "http://sso/api", "x".into())
+                .build()
+                .unwrap();
+            assert_eq!(request.method(), method);
+            assert!(request.body().is_some());
+        }
+        let request = upstream_request(&client, &Method::HEAD, "http://sso/api", "x".into())
+            .build()
+            .unwrap();
+        assert!(request.body().is_none());
+    }
+
+    #[test]
+    fn flat_changes_keep_untouched_json() {
+        let mut body = json!({
+            "messages": [{"role": "user", "content": "hi"}],
+            "tools": [],
+            "metadata": {},
+            "x-trace": {"a-b": 1},
+            "user": "u1",
+        });
+        let mut before = HashMap::new();
+        json_to_flat_map(&body, "", &mut before);
+        let mut after = before.clone();
+        after.remove("user");
+        after.insert("inputs.lang".to_string(), json!("zh"));
+        apply_flat_changes(&mut body, &before, &after);
+        assert_eq!(
+            body,
+            json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "tools": [],
+                "metadata": {},
+                "x-trace": {"a-b": 1},
+                "inputs": {"lang": "zh"},
+            })
+        );
+    }
 }
\ No newline at end of file
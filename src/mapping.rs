@@ -0,0 +1,708 @@
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tracing::{event, Level};
+
+use crate::config::{
+    ClaimRule, ClaimsConfig, MixAction, MixMapping, MixSource, MixTarget, PluginConfig,
+    RequestMapConfig, ResponseMapConfig, ScriptConfig, ServiceType, Transformation,
+};
+use crate::transform::apply_value_transformations;
+use crate::{flat_map_to_json, json_body_to_string, json_to_flat_map, parse_key_path};
+use crate::{plugin, script};
+
+/// 映射作用的请求/响应各部分，response 没有 query；
+/// vars 为单次请求内的变量，request 阶段写入后 response 阶段可读
+pub struct MappingParts<'a> {
+    pub headers: &'a mut HeaderMap,
+    pub query: Option<&'a mut HashMap<String, Vec<String>>>,
+    pub body: &'a mut HashMap<String, Value>,
+    pub vars: &'a mut HashMap<String, Value>,
+}
+
+/// required 的源字段缺失
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingField(pub String);
+
+impl MixSource {
+    pub fn name(&self) -> &str {
+        match self {
+            MixSource::Header(name)
+            | MixSource::BodyField(name)
+            | MixSource::Query(name)
+            | MixSource::Var(name) => name,
+        }
+    }
+}
+
+impl MixMapping {
+    pub fn involves_body(&self) -> bool {
+        matches!(self.source, MixSource::BodyField(_)) || matches!(self.target, MixTarget::BodyField(_))
+    }
+}
+
+impl RequestMapConfig {
+    /// 是否需要读取并处理请求 body
+    pub fn needs_body(&self) -> bool {
+        self.body_conversion.is_some()
+            || self.mix_mappings.iter().any(MixMapping::involves_body)
+            || self.script.is_some()
+            || self.plugin.is_some()
+            || matches!(
+                self.target_service,
+                ServiceType::OpenAi(_) | ServiceType::OAuth2Adapter(_)
+            )
+            || matches!(crate::sse::stream_source(self), Some(MixSource::BodyField(_)))
+    }
+}
+
+impl ResponseMapConfig {
+    /// 是否需要读取并处理响应 body
+    pub fn needs_body(&self) -> bool {
+        self.body_conversion.is_some()
+            || self.mix_mappings.iter().any(MixMapping::involves_body)
+            || self.script.is_some()
+            || self.plugin.is_some()
+            || self.claims.is_some()
+    }
+}
+
+/// 不涉及 body 的映射，用于不读取 body 的流式响应
+pub fn header_mappings(mappings: &[MixMapping]) -> Vec<MixMapping> {
+    mappings
+        .iter()
+        .filter(|m| !m.involves_body())
+        .cloned()
+        .collect()
+}
+
+/// 阶段处理失败
+#[derive(Debug)]
+pub enum StageError {
+    MissingField(String),
+    Script(anyhow::Error),
+    Plugin(anyhow::Error),
+}
+
+impl std::fmt::Display for StageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StageError::MissingField(field) => write!(f, "Missing required field: {}", field),
+            StageError::Script(e) => write!(f, "Script failed: {}", e),
+            StageError::Plugin(e) => write!(f, "Plugin failed: {}", e),
+        }
+    }
+}
+
+/// 一个阶段（request / response / sse 事件）的处理：mix_mappings → 脚本 → 插件
+pub fn run_stage(
+    mappings: &[MixMapping],
+    script: Option<&ScriptConfig>,
+    plugin: Option<&PluginConfig>,
+    stage: &str,
+    parts: &mut MappingParts,
+) -> Result<(), StageError> {
+    apply_mix_mappings(mappings, parts).map_err(|MissingField(f)| StageError::MissingField(f))?;
+    if let Some(conf) = script {
+        script::run_stage_script(conf, parts).map_err(StageError::Script)?;
+    }
+    if let Some(conf) = plugin {
+        plugin::run_stage_plugin(conf, stage, parts).map_err(StageError::Plugin)?;
+    }
+    Ok(())
+}
+
+// 声明规则展开为 (来源, 常量, 转换, 是否必需)
+fn claim_rule(rule: &ClaimRule) -> (Option<&String>, Option<&Value>, &[Transformation], bool) {
+    match rule {
+        ClaimRule::From(from) => (Some(from), None, &[], false),
+        ClaimRule::Rule {
+            from,
+            value,
+            transformations,
+            required,
+        } => (from.as_ref(), value.as_ref(), transformations, *required),
+    }
+}
+
+/// 按声明映射生成新的 body，数组和对象保持原结构
+pub fn apply_claims(
+    conf: &ClaimsConfig,
+    body: &mut HashMap<String, Value>,
+) -> Result<(), MissingField> {
+    let mut output = HashMap::new();
+    if conf.keep_unmapped {
+        // 被映射的来源字段不再保留
+        output = body.clone();
+        for rule in conf.map.values() {
+            if let (Some(from), ..) = claim_rule(rule) {
+                body_remove(&mut output, from);
+            }
+        }
+    }
+    for (claim, rule) in &conf.map {
+        let (from, constant, transformations, required) = claim_rule(rule);
+        let value = from
+            .and_then(|from| body_get(body, from))
+            .and_then(|value| {
+                if transformations.is_empty() {
+                    Some(value)
+                } else {
+                    apply_value_transformations(transformations, &value, None)
+                }
+            })
+            .or_else(|| constant.cloned());
+        match value {
+            Some(value) => body_set(&mut output, claim, value),
+            None if required => return Err(MissingField(claim.clone())),
+            None => {}
+        }
+    }
+    *body = output;
+    Ok(())
+}
+
+// 依次处理 mix_mappings
+pub fn apply_mix_mappings(
+    mappings: &[MixMapping],
+    parts: &mut MappingParts,
+) -> Result<(), MissingField> {
+    for mapping in mappings {
+        let value = match &mapping.action {
+            MixAction::AddTarget(v) => Some(Value::String(v.clone())),
+            MixAction::Copy => read_source(parts, &mapping.source),
+            MixAction::Move => take_source(parts, &mapping.source),
+            MixAction::DeleteSrc => {
+                take_source(parts, &mapping.source);
+                None
+            }
+        };
+        let value = match value {
+            Some(mut value) => {
+                if let Some(trans) = &mapping.transformations {
+                    let dst_val = read_target(parts, &mapping.target).map(|v| value_to_string(&v));
+                    match apply_value_transformations(trans, &value, dst_val.as_deref()) {
+                        Some(transformed) => value = transformed,
+                        // 转换结果为空时使用默认值，未配置则保留原值
+                        None => {
+                            if let Some(default) = &mapping.default {
+                                value = default.clone();
+                            }
+                        }
+                    }
+                }
+                Some(value)
+            }
+            None if mapping.action == MixAction::DeleteSrc => None,
+            None => mapping.default.clone(),
+        };
+
+        match value {
+            // body 到 body 的 add_target 与原实现一致，写入源字段
+            Some(value) => match (&mapping.action, &mapping.source, &mapping.target) {
+                (MixAction::AddTarget(_), MixSource::BodyField(src), MixTarget::BodyField(_)) => {
+                    write_target(parts, &MixTarget::BodyField(src.clone()), value)
+                }
+                _ => write_target(parts, &mapping.target, value),
+            },
+            None if mapping.required => {
+                event!(Level::WARN, "Required field missing: {:?}", mapping.source);
+                return Err(MissingField(mapping.source.name().to_string()));
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn read_source(parts: &MappingParts, source: &MixSource) -> Option<Value> {
+    match source {
+        MixSource::Header(name) => header_get(parts.headers, name),
+        MixSource::Query(name) => parts.query.as_deref().and_then(|q| query_get(q, name)),
+        MixSource::BodyField(key) => body_get(parts.body, key),
+        MixSource::Var(name) => body_get(parts.vars, name),
+    }
+}
+
+pub fn take_source(parts: &mut MappingParts, source: &MixSource) -> Option<Value> {
+    let value = read_source(parts, source);
+    match source {
+        MixSource::Header(name) => {
+            parts.headers.remove(name.as_str());
+        }
+        MixSource::Query(name) => {
+            if let Some(q) = parts.query.as_deref_mut() {
+                q.remove(name);
+            }
+        }
+        MixSource::BodyField(key) => body_remove(parts.body, key),
+        MixSource::Var(name) => body_remove(parts.vars, name),
+    }
+    value
+}
+
+fn read_target(parts: &MappingParts, target: &MixTarget) -> Option<Value> {
+    match target {
+        MixTarget::Header(name) => header_get(parts.headers, name),
+        MixTarget::Query(name) => parts.query.as_deref().and_then(|q| query_get(q, name)),
+        MixTarget::BodyField(key) => body_get(parts.body, key),
+        MixTarget::Var(name) => parts.vars.get(name).cloned(),
+    }
+}
+
+fn write_target(parts: &mut MappingParts, target: &MixTarget, value: Value) {
+    match target {
+        MixTarget::Header(name) => header_set(parts.headers, name, &value),
+        MixTarget::Query(name) => match parts.query.as_deref_mut() {
+            Some(q) => {
+                q.insert(name.clone(), value_to_strings(&value));
+            }
+            None => event!(Level::WARN, "Query target {} ignored: no query here", name),
+        },
+        MixTarget::BodyField(key) => body_set(parts.body, key, value),
+        MixTarget::Var(name) => {
+            parts.vars.insert(name.clone(), value);
+        }
+    }
+}
+
+// 单值转字符串，对象按 "k=v; k=v" 展开
+pub fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(arr) => arr
+            .iter()
+            .map(value_to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::Object(_) => {
+            let mut flat = HashMap::new();
+            json_to_flat_map(value, "", &mut flat);
+            json_body_to_string(&flat, "{key}={value}")
+        }
+        other => other.to_string(),
+    }
+}
+
+// 多值（query / header）
+pub fn value_to_strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(arr) => arr.iter().map(value_to_string).collect(),
+        other => vec![value_to_string(other)],
+    }
+}
+
+pub fn header_get(headers: &HeaderMap, name: &str) -> Option<Value> {
+    let values: Vec<Value> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(|s| Value::String(s.to_string()))
+        .collect();
+    match values.len() {
+        0 => None,
+        1 => values.into_iter().next(),
+        _ => Some(Value::Array(values)),
+    }
+}
+
+// 数组写为重复 header
+fn header_set(headers: &mut HeaderMap, name: &str, value: &Value) {
+    let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+        event!(Level::WARN, "Invalid header name: {}", name);
+        return;
+    };
+    headers.remove(&name);
+    for v in value_to_strings(value) {
+        match HeaderValue::from_str(&v) {
+            Ok(hv) => {
+                headers.append(&name, hv);
+            }
+            Err(e) => event!(Level::WARN, "Invalid header value for {}: {}", name, e),
+        }
+    }
+}
+
+pub fn query_get(query: &HashMap<String, Vec<String>>, name: &str) -> Option<Value> {
+    let values = query.get(name)?;
+    match values.len() {
+        0 => None,
+        1 => Some(Value::String(values[0].clone())),
+        _ => Some(Value::Array(
+            values.iter().cloned().map(Value::String).collect(),
+        )),
+    }
+}
+
+// 判断扁平 key 是否属于 key 的子字段，如 "a" 的 "a.b" / "a[0]"
+fn is_sub_key(flat_key: &str, key: &str) -> bool {
+    flat_key
+        .strip_prefix(key)
+        .is_some_and(|rest| rest.starts_with('.') || rest.starts_with('['))
+}
+
+// 读取 body 字段，子字段还原为对象/数组
+pub fn body_get(body: &HashMap<String, Value>, key: &str) -> Option<Value> {
+    if let Some(v) = body.get(key) {
+        return Some(v.clone());
+    }
+    let sub: HashMap<String, Value> = body
+        .iter()
+        .filter(|(k, _)| is_sub_key(k, key))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if sub.is_empty() {
+        return None;
+    }
+    let mut current = flat_map_to_json(&sub);
+    for part in parse_key_path(key) {
+        current = match (current, part.parse::<usize>()) {
+            (Value::Array(mut arr), Ok(i)) if i < arr.len() => arr.swap_remove(i),
+            (Value::Object(mut obj), _) => obj.remove(part)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+pub fn body_remove(body: &mut HashMap<String, Value>, key: &str) {
+    body.retain(|k, _| k != key && !is_sub_key(k, key));
+}
+
+// 写入 body 字段，对象/数组按扁平 key 展开
+pub fn body_set(body: &mut HashMap<String, Value>, key: &str, value: Value) {
+    body_remove(body, key);
+    match value {
+        Value::Array(_) | Value::Object(_) => json_to_flat_map(&value, key, body),
+        scalar => {
+            body.insert(key.to_string(), scalar);
+        }
+    }
+}
+
+/// 将各部分转为 json：{"headers": {}, "query": {}, "body": {}, "vars": {}}，
+/// 多值 header/query 为数组，body 为扁平 key；供脚本和插件使用
+pub fn parts_to_json(parts: &MappingParts) -> Value {
+    let headers: Map<String, Value> = parts
+        .headers
+        .keys()
+        .filter_map(|name| Some((name.to_string(), header_get(parts.headers, name.as_str())?)))
+        .collect();
+    let query: Map<String, Value> = parts
+        .query
+        .as_deref()
+        .map(|q| {
+            q.keys()
+                .filter_map(|k| Some((k.clone(), query_get(q, k)?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let body: Map<String, Value> = parts.body.clone().into_iter().collect();
+    let vars: Map<String, Value> = parts.vars.clone().into_iter().collect();
+
+    serde_json::json!({
+        "headers": headers,
+        "query": query,
+        "body": body,
+        "vars": vars,
+    })
+}
+
+/// 回写 parts_to_json 格式的结果，只替换结果中出现的部分
+pub fn apply_parts_json(parts: &mut MappingParts, value: Value) -> anyhow::Result<()> {
+    let Value::Object(mut output) = value else {
+        anyhow::bail!("parts must be a json object");
+    };
+    let mut take = |name: &str| -> anyhow::Result<Option<Map<String, Value>>> {
+        match output.remove(name) {
+            Some(Value::Object(map)) => Ok(Some(map)),
+            None | Some(Value::Null) => Ok(None),
+            Some(_) => anyhow::bail!("{} must be a json object", name),
+        }
+    };
+
+    if let Some(headers) = take("headers")? {
+        let mut headers_map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+            for v in value_to_strings(&value) {
+                headers_map.append(&name, HeaderValue::from_str(&v)?);
+            }
+        }
+        *parts.headers = headers_map;
+    }
+    if let Some(query) = take("query")? {
+        if let Some(q) = parts.query.as_deref_mut() {
+            *q = query
+                .into_iter()
+                .map(|(k, v)| (k, value_to_strings(&v)))
+                .collect();
+        }
+    }
+    if let Some(body) = take("body")? {
+        *parts.body = body.into_iter().collect();
+    }
+    if let Some(vars) = take("vars")? {
+        *parts.vars = vars.into_iter().collect();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn claims_rename_filter_and_constants() {
+        let mut body = HashMap::new();
+        json_to_flat_map(
+            &json!({
+                "uid": "IYANG10",
+                "mail": "irene@example.com",
+                "displayName": "YANG Irene",
+                "emails": ["a@example.com", "b@example.com"],
+                "role": [
+                    "id=VALIDATE,id=FRANCHISE,id=appRole",
+                    "id=Admin,id=TATTOO,id=appRole",
+                    "id=Admin,id=DIFY,id=appRole"
+                ]
+            }),
+            "",
+            &mut body,
+        );
+        let conf: ClaimsConfig = serde_yaml::from_str(
+            r#"
+map:
+  sub: uid
+  email: mail
+  name: displayName
+  primary_email:
+    from: emails
+    transformations:
+    - type: first
+  roles:
+    from: role
+    transformations:
+    - type: filter
+      regex: "^id=Admin,"
+  email_verified:
+    value: true
+"#,
+        )
+        .unwrap();
+        apply_claims(&conf, &mut body).unwrap();
+        assert_eq!(
+            flat_map_to_json(&body),
+            json!({
+                "sub": "IYANG10",
+                "email": "irene@example.com",
+                "name": "YANG Irene",
+                "primary_email": "a@example.com",
+                "roles": ["id=Admin,id=TATTOO,id=appRole", "id=Admin,id=DIFY,id=appRole"],
+                "email_verified": true
+            })
+        );
+
+        let required: ClaimsConfig =
+            serde_yaml::from_str("map: {sub: {from: uid, required: true}}").unwrap();
+        assert!(apply_claims(&required, &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn body_array_move_to_repeated_query() {
+        let mut headers = HeaderMap::new();
+        let mut query = HashMap::new();
+        let mut body = HashMap::new();
+        json_to_flat_map(&json!({"role": ["a", "b"], "uid": "u1"}), "", &mut body);
+
+        let mappings: Vec<MixMapping> = serde_yaml::from_str(
+            r#"
+- source: !bodyfield role
+  target: !query role
+  action: move
+  transformations:
+  - type: uppercase
+- source: !bodyfield uid
+  target: !header x-user
+  action: copy
+"#,
+        )
+        .unwrap();
+        apply_mix_mappings(
+            &mappings,
+            &mut MappingParts {
+                headers: &mut headers,
+                query: Some(&mut query),
+                body: &mut body,
+                vars: &mut HashMap::new(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            query.get("role"),
+            Some(&vec!["A".to_string(), "B".to_string()])
+        );
+        assert_eq!(headers.get("x-user").unwrap(), "u1");
+        assert_eq!(body.len(), 1);
+    }
+
+    #[test]
+    fn body_add_target_writes_source_field() {
+        let mut body = HashMap::new();
+        json_to_flat_map(&json!({"grant_type": "code"}), "", &mut body);
+        let mappings: Vec<MixMapping> = serde_yaml::from_str(
+            r#"
+- source: !bodyfield grant_type
+  target: !bodyfield type
+  action: !addtarget authorization_code
+- source: !bodyfield uid
+  target: !header x-user
+  action: !addtarget anonymous
+"#,
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        apply_mix_mappings(
+            &mappings,
+            &mut MappingParts {
+                headers: &mut headers,
+                query: None,
+                body: &mut body,
+                vars: &mut HashMap::new(),
+            },
+        )
+        .unwrap();
+        assert_eq!(body.get("grant_type"), Some(&json!("authorization_code")));
+        assert!(!body.contains_key("type"));
+        assert_eq!(headers.get("x-user").unwrap(), "anonymous");
+    }
+
+    // 与原先逐对实现的行为一致：header / query / body 两两组合，每种 action
+    #[test]
+    fn source_target_pairs_match_original() {
+        let sources = [
+            ("!header x-src", "h"),
+            ("!query src", "q"),
+            ("!bodyfield src", "b"),
+        ];
+        let targets = ["!header x-dst", "!query dst", "!bodyfield dst"];
+        for (source, src_value) in sources {
+            for target in targets {
+                for action in ["move", "copy", "!addtarget added", "deletesrc"] {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("x-src", HeaderValue::from_static("h"));
+                    let mut query = HashMap::from([("src".to_string(), vec!["q".to_string()])]);
+                    let mut body = HashMap::from([("src".to_string(), json!("b"))]);
+                    let mappings: Vec<MixMapping> = serde_yaml::from_str(&format!(
+                        "- {{source: {}, target: {}, action: {}}}",
+                        source, target, action
+                    ))
+                    .unwrap();
+                    let mut parts = MappingParts {
+                        headers: &mut headers,
+                        query: Some(&mut query),
+                        body: &mut body,
+                        vars: &mut HashMap::new(),
+                    };
+                    apply_mix_mappings(&mappings, &mut parts).unwrap();
+
+                    let mapping = &mappings[0];
+                    let case = format!("{} -> {} ({})", source, target, action);
+                    let body_to_body =
+                        source.starts_with("!bodyfield") && target.starts_with("!bodyfield");
+                    let (src_after, dst_after) = match action {
+                        "move" => (None, Some(src_value)),
+                        "copy" => (Some(src_value), Some(src_value)),
+                        "deletesrc" => (None, None),
+                        // body 到 body 的 add_target 写入源字段
+                        _ if body_to_body => (Some("added"), None),
+                        _ => (Some(src_value), Some("added")),
+                    };
+                    assert_eq!(
+                        read_source(&parts, &mapping.source),
+                        src_after.map(Value::from),
+                        "source of {}",
+                        case
+                    );
+                    assert_eq!(
+                        read_target(&parts, &mapping.target),
+                        dst_after.map(Value::from),
+                        "target of {}",
+                        case
+                    );
+                }
+            }
+        }
+
+        // body 对象写入 header / query 时按 "k=v; k=v" 展开，写入 body 时保留子字段
+        let mappings: Vec<MixMapping> = serde_yaml::from_str(
+            r#"
+- {source: !bodyfield obj, target: !header x-obj, action: copy}
+- {source: !bodyfield obj, target: !query obj, action: copy}
+- {source: !bodyfield obj, target: !bodyfield dst, action: move}
+"#,
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        let mut query = HashMap::new();
+        let mut body = HashMap::new();
+        json_to_flat_map(&json!({"obj": {"k2": "2", "k1": "1"}}), "", &mut body);
+        apply_mix_mappings(
+            &mappings,
+            &mut MappingParts {
+                headers: &mut headers,
+                query: Some(&mut query),
+                body: &mut body,
+                vars: &mut HashMap::new(),
+            },
+        )
+        .unwrap();
+        assert_eq!(headers.get("x-obj").unwrap(), "k1=1; k2=2");
+        assert_eq!(query.get("obj"), Some(&vec!["k1=1; k2=2".to_string()]));
+        assert_eq!(
+            flat_map_to_json(&body),
+            json!({"dst": {"k1": "1", "k2": "2"}})
+        );
+    }
+
+    #[test]
+    fn default_and_required() {
+        let mut headers = HeaderMap::new();
+        let mut query = HashMap::new();
+        let mut body = HashMap::new();
+
+        let mappings: Vec<MixMapping> = serde_yaml::from_str(
+            r#"
+- source: !bodyfield grant_type
+  target: !query grant_type
+  action: move
+  default: authorization_code
+- source: !bodyfield code
+  target: !query code
+  action: move
+  required: true
+"#,
+        )
+        .unwrap();
+        let result = apply_mix_mappings(
+            &mappings,
+            &mut MappingParts {
+                headers: &mut headers,
+                query: Some(&mut query),
+                body: &mut body,
+                vars: &mut HashMap::new(),
+            },
+        );
+
+        assert_eq!(
+            query.get("grant_type"),
+            Some(&vec!["authorization_code".to_string()])
+        );
+        assert_eq!(result, Err(MissingField("code".to_string())));
+    }
+}
@@ -0,0 +1,66 @@
+// Prometheus 文本格式的运行指标，各模块在输出时提供当前状态
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::fmt::Write;
+
+use crate::{breaker, pool, upstream};
+
+/// 一个样本：标签和值
+pub type Sample<'a> = (Vec<(&'a str, &'a str)>, f64);
+
+/// 指标输出
+#[derive(Default)]
+pub struct Metrics(String);
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str, samples: &[Sample]) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect::<Vec<_>>()
+                .join(",");
+            if labels.is_empty() {
+                let _ = writeln!(self.0, "{} {}", name, value);
+            } else {
+                let _ = writeln!(self.0, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, samples: &[Sample]) {
+        self.family(name, "gauge", help, samples);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, samples: &[Sample]) {
+        self.family(name, "counter", help, samples);
+    }
+}
+
+/// 输出所有指标
+pub fn render() -> String {
+    let mut metrics = Metrics::default();
+    upstream::write_metrics(&mut metrics);
+    breaker::write_metrics(&mut metrics);
+    pool::write_metrics(&mut metrics);
+    metrics.0
+}
+
+/// 指标路径时返回响应
+pub fn handle(path: &str, metrics_path: Option<&String>) -> Option<Response> {
+    if metrics_path.map(String::as_str) != Some(path) {
+        return None;
+    }
+    Some(
+        (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            render(),
+        )
+            .into_response(),
+    )
+}
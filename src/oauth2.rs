@@ -0,0 +1,797 @@
+// 非标准 OAuth2 上游到 RFC 6749 / OIDC 端点的适配：
+// - token：支持 client_secret_basic / client_secret_post，参数（含 PKCE code_verifier）透传，
+//   响应统一为 json，错误统一为 {"error", "error_description"}
+// - authorize：校验参数后重定向到上游，state、nonce、code_challenge 等原样透传
+// - userinfo：从 Bearer / access_token 参数取 token，按配置位置发给上游
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tracing::{event, Level};
+use url::form_urlencoded;
+
+use crate::config::{
+    OAuth2AdapterConfig, OAuth2ClientAuth, OAuth2Endpoint, OAuth2Method, OAuth2ParamLocation,
+    OAuth2TokenLocation, PathConfig,
+};
+use crate::mapping::{self, MappingParts, StageError};
+use crate::{
+    flat_map_to_json, json_to_flat_map, multimap_to_query, query_to_multimap, send_upstream,
+    UpstreamCall,
+};
+
+// 不转发给上游的客户端 header
+const DROP_HEADERS: [&str; 7] = [
+    "host",
+    "authorization",
+    "content-length",
+    "content-type",
+    "accept-encoding",
+    "connection",
+    "transfer-encoding",
+];
+
+/// RFC 6749 5.2 错误响应
+pub fn oauth_error(status: StatusCode, error: &str, description: &str) -> Response {
+    let mut body = json!({ "error": error });
+    if !description.is_empty() {
+        body["error_description"] = Value::from(description);
+    }
+    let mut response = json_response(status, &body);
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"oauth2\""),
+        );
+    }
+    response
+}
+
+/// RFC 6750 3 错误响应
+pub fn bearer_error(error: Option<&str>, description: &str) -> Response {
+    let challenge = match error {
+        Some(error) => format!(
+            "Bearer error=\"{}\", error_description=\"{}\"",
+            error,
+            description.replace('"', "'")
+        ),
+        None => "Bearer".to_string(),
+    };
+    let mut response = json_response(
+        StatusCode::UNAUTHORIZED,
+        &json!({ "error": error.unwrap_or("invalid_request"), "error_description": description }),
+    );
+    if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response {
+    (
+        status,
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CACHE_CONTROL, "no-store"),
+            (header::PRAGMA, "no-cache"),
+        ],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// 解析 form 或 json 参数
+pub fn parse_params(headers: &HeaderMap, body: &[u8]) -> HashMap<String, Value> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(mime::APPLICATION_JSON.essence_str()));
+    let mut params = HashMap::new();
+    if is_json {
+        if let Ok(data) = serde_json::from_slice::<Value>(body) {
+            json_to_flat_map(&data, "", &mut params);
+        }
+    } else {
+        for (key, value) in form_urlencoded::parse(body) {
+            params.insert(key.into_owned(), Value::String(value.into_owned()));
+        }
+    }
+    params
+}
+
+/// 上游响应可能是 json 或 form，都转为 json 对象
+pub fn parse_upstream_body(body: &[u8]) -> Map<String, Value> {
+    if let Ok(Value::Object(map)) = serde_json::from_slice::<Value>(body) {
+        return map;
+    }
+    form_urlencoded::parse(body)
+        .filter(|(k, _)| !k.is_empty())
+        .map(|(k, v)| (k.into_owned(), Value::String(v.into_owned())))
+        .collect()
+}
+
+// RFC 6749 2.3.1，Basic 中的 id 和 secret 为 form 编码
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value
+        .strip_prefix("Basic ")
+        .or_else(|| value.strip_prefix("basic "))?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    let decode = |s: &str| {
+        form_urlencoded::parse(format!("v={}", s).as_bytes())
+            .next()
+            .map(|(_, v)| v.into_owned())
+            .unwrap_or_default()
+    };
+    Some((decode(id), decode(secret)))
+}
+
+/// 读取客户端凭证，同时使用多种方式时返回错误
+fn client_credentials(
+    headers: &HeaderMap,
+    params: &mut HashMap<String, Value>,
+) -> Result<Option<(String, Option<String>)>, &'static str> {
+    let basic = basic_credentials(headers);
+    let post_id = params
+        .remove("client_id")
+        .map(|v| mapping::value_to_string(&v));
+    let post_secret = params
+        .remove("client_secret")
+        .map(|v| mapping::value_to_string(&v));
+    match (basic, post_id) {
+        (Some(_), Some(_)) if post_secret.is_some() => {
+            Err("multiple client authentication methods")
+        }
+        (Some((id, secret)), _) => Ok(Some((id, Some(secret)))),
+        (None, Some(id)) => Ok(Some((id, post_secret))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn string_field<'a>(map: &'a Map<String, Value>, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| map.get(*name).and_then(Value::as_str))
+        .filter(|s| !s.is_empty())
+}
+
+// 上游在 body 中返回的业务错误码，数字或数字字符串
+fn body_code(map: &Map<String, Value>) -> Option<u16> {
+    ["code", "errcode", "error_code", "status"]
+        .iter()
+        .find_map(|name| match map.get(*name)? {
+            Value::Number(n) => n.as_u64().and_then(|n| u16::try_from(n).ok()),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        })
+}
+
+/// 上游 token 响应规范化，返回 (状态码, json)
+pub fn normalize_token_response(
+    status: StatusCode,
+    body: Map<String, Value>,
+) -> (StatusCode, Value) {
+    let description = string_field(
+        &body,
+        &["error_description", "message", "msg", "error_msg", "errmsg"],
+    )
+    .unwrap_or_default()
+    .to_string();
+
+    if status.is_server_error() {
+        return (
+            StatusCode::BAD_GATEWAY,
+            json!({ "error": "server_error", "error_description": description }),
+        );
+    }
+    if let Some(error) = string_field(&body, &["error"]) {
+        let status = if error == "invalid_client" {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        return (
+            status,
+            json!({ "error": error, "error_description": description }),
+        );
+    }
+    if !status.is_success() || string_field(&body, &["access_token"]).is_none() {
+        // HTTP 状态码或 body 中的错误码为 401 时是客户端认证失败（RFC 6749 §5.2）
+        let client_failed = status == StatusCode::UNAUTHORIZED || body_code(&body) == Some(401);
+        let (status, error) = if client_failed {
+            (StatusCode::UNAUTHORIZED, "invalid_client")
+        } else {
+            (StatusCode::BAD_REQUEST, "invalid_grant")
+        };
+        return (
+            status,
+            json!({ "error": error, "error_description": description }),
+        );
+    }
+
+    let mut token = body;
+    let token_type = string_field(&token, &["token_type"]).unwrap_or("Bearer");
+    let token_type = if token_type.eq_ignore_ascii_case("bearer") {
+        "Bearer".to_string()
+    } else {
+        token_type.to_string()
+    };
+    token.insert("token_type".to_string(), Value::from(token_type));
+    if let Some(expires_in) = token
+        .get("expires_in")
+        .and_then(Value::as_str)
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        token.insert("expires_in".to_string(), Value::from(expires_in));
+    }
+    if let Some(Value::Array(scopes)) = token.get("scope") {
+        let scope = scopes
+            .iter()
+            .map(mapping::value_to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        token.insert("scope".to_string(), Value::from(scope));
+    }
+    (StatusCode::OK, Value::Object(token))
+}
+
+fn forward_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut forwarded = headers.clone();
+    for name in DROP_HEADERS {
+        forwarded.remove(name);
+    }
+    forwarded.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    forwarded
+}
+
+fn stage_error(e: StageError) -> Response {
+    match e {
+        StageError::MissingField(field) => oauth_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            &format!("missing {}", field),
+        ),
+        e => {
+            event!(Level::ERROR, "OAuth2 adapter stage failed: {}", e);
+            oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "")
+        }
+    }
+}
+
+/// 适配入口，base_url 为上游地址
+#[allow(clippy::too_many_arguments)]
+pub async fn handle(
+    conf: &OAuth2AdapterConfig,
+    route: &PathConfig,
+    client: Client,
+    call: UpstreamCall<'_>,
+    base_url: &str,
+    path: &str,
+    method: &Method,
+    headers: &HeaderMap,
+    query: Option<&str>,
+    body: &Bytes,
+    vars: &mut HashMap<String, Value>,
+) -> Result<Response, (StatusCode, String)> {
+    let base_url = conf.upstream_url.as_deref().unwrap_or(base_url);
+    let upstream_url = format!(
+        "{}{}",
+        base_url,
+        conf.upstream_path.as_deref().unwrap_or(path)
+    );
+    let mut params = parse_params(headers, body);
+    let mut upstream_headers = forward_headers(headers);
+    let mut upstream_query = HashMap::new();
+
+    match conf.endpoint {
+        OAuth2Endpoint::Token => {
+            if method != Method::POST {
+                return Ok(oauth_error(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "invalid_request",
+                    "token endpoint requires POST",
+                ));
+            }
+            let credentials = match client_credentials(headers, &mut params) {
+                Ok(c) => c,
+                Err(e) => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", e)),
+            };
+            let grant_type = params.get("grant_type").map(mapping::value_to_string);
+            let required = match grant_type.as_deref() {
+                None => Some("grant_type"),
+                Some("authorization_code") => Some("code"),
+                Some("refresh_token") => Some("refresh_token"),
+                Some(_) => None,
+            };
+            if let Some(field) = required.filter(|f| !params.contains_key(*f)) {
+                return Ok(oauth_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("missing {}", field),
+                ));
+            }
+            if let Some((id, secret)) = credentials {
+                match conf.client_auth {
+                    OAuth2ClientAuth::Params => {
+                        params.insert("client_id".to_string(), Value::from(id));
+                        if let Some(secret) = secret {
+                            params.insert("client_secret".to_string(), Value::from(secret));
+                        }
+                    }
+                    OAuth2ClientAuth::Basic => {
+                        let encoded = BASE64_STANDARD.encode(format!(
+                            "{}:{}",
+                            urlencoding::encode(&id),
+                            urlencoding::encode(&secret.unwrap_or_default())
+                        ));
+                        if let Ok(value) = HeaderValue::from_str(&format!("Basic {}", encoded)) {
+                            upstream_headers.insert(header::AUTHORIZATION, value);
+                        }
+                    }
+                }
+            }
+        }
+        OAuth2Endpoint::Authorize => {
+            let mut all = query.map(query_to_multimap).unwrap_or_default();
+            for (key, value) in params.drain() {
+                all.entry(key)
+                    .or_insert_with(|| vec![mapping::value_to_string(&value)]);
+            }
+            for field in ["response_type", "client_id"] {
+                if !all.contains_key(field) {
+                    return Ok(oauth_error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_request",
+                        &format!("missing {}", field),
+                    ));
+                }
+            }
+            if let Err(e) = mapping::run_stage(
+                &route.request.mix_mappings,
+                route.request.script.as_ref(),
+                route.request.plugin.as_ref(),
+                "request",
+                &mut MappingParts {
+                    headers: &mut upstream_headers,
+                    query: Some(&mut all),
+                    body: &mut HashMap::new(),
+                    vars,
+                },
+            ) {
+                return Ok(stage_error(e));
+            }
+            let location = format!("{}?{}", upstream_url, multimap_to_query(&all));
+            event!(Level::DEBUG, "OAuth2 authorize redirect: {}", location);
+            let location = HeaderValue::from_str(&location)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            return Ok((StatusCode::FOUND, [(header::LOCATION, location)]).into_response());
+        }
+        OAuth2Endpoint::Userinfo => {
+            let bearer = headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| {
+                    v.strip_prefix("Bearer ")
+                        .or_else(|| v.strip_prefix("bearer "))
+                })
+                .map(str::to_string);
+            let token = bearer
+                .or_else(|| {
+                    params
+                        .remove("access_token")
+                        .map(|v| mapping::value_to_string(&v))
+                })
+                .or_else(|| {
+                    query
+                        .map(query_to_multimap)
+                        .and_then(|mut q| q.remove("access_token"))
+                        .and_then(|v| v.into_iter().next())
+                });
+            let Some(token) = token else {
+                return Ok(bearer_error(None, "missing access token"));
+            };
+            params.clear();
+            match conf.token_in {
+                OAuth2TokenLocation::Header => {
+                    if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token)) {
+                        upstream_headers.insert(header::AUTHORIZATION, value);
+                    }
+                }
+                OAuth2TokenLocation::Query => {
+                    upstream_query.insert("access_token".to_string(), vec![token]);
+                }
+                OAuth2TokenLocation::Form => {
+                    params.insert("access_token".to_string(), Value::from(token));
+                }
+            }
+        }
+    }
+
+    if let Err(e) = mapping::run_stage(
+        &route.request.mix_mappings,
+        route.request.script.as_ref(),
+        route.request.plugin.as_ref(),
+        "request",
+        &mut MappingParts {
+            headers: &mut upstream_headers,
+            query: Some(&mut upstream_query),
+            body: &mut params,
+            vars,
+        },
+    ) {
+        return Ok(stage_error(e));
+    }
+
+    // 组装上游请求
+    let mut params_in = conf.params_in;
+    if conf.upstream_method == OAuth2Method::Get {
+        params_in = OAuth2ParamLocation::Query;
+    }
+    let mut body_bytes = Vec::new();
+    match params_in {
+        OAuth2ParamLocation::Query => {
+            for (key, value) in params.iter() {
+                upstream_query.insert(key.clone(), mapping::value_to_strings(value));
+            }
+        }
+        OAuth2ParamLocation::Form => {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            for (key, value) in params.iter() {
+                form.append_pair(key, &mapping::value_to_string(value));
+            }
+            body_bytes = form.finish().into_bytes();
+            upstream_headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-www-form-urlencoded"),
+            );
+        }
+        OAuth2ParamLocation::Json => {
+            body_bytes = flat_map_to_json(&params).to_string().into_bytes();
+            upstream_headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+        }
+    }
+    let url = if upstream_query.is_empty() {
+        upstream_url
+    } else {
+        format!("{}?{}", upstream_url, multimap_to_query(&upstream_query))
+    };
+    let body_bytes = Bytes::from(body_bytes);
+    event!(Level::DEBUG, "OAuth2 adapter upstream request: {}", url);
+    // 按发往上游的方法判断能否重试
+    let upstream_method = match conf.upstream_method {
+        OAuth2Method::Get => Method::GET,
+        OAuth2Method::Post => Method::POST,
+    };
+    let call = UpstreamCall {
+        retry: call.retry.filter(|r| r.allows(&upstream_method)),
+        ..call
+    };
+    let response = send_upstream(call, || {
+        let request = match conf.upstream_method {
+            OAuth2Method::Get => client.get(&url),
+            OAuth2Method::Post => client.post(&url).body(body_bytes.clone()),
+        };
+        request.headers(upstream_headers.clone())
+    })
+    .await?;
+    let status = response.status();
+    let mut res_headers = response.headers().clone();
+    let res_body = response
+        .bytes()
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Body read failed: {}", e)))?;
+    let upstream_body = parse_upstream_body(&res_body);
+
+    let (status, normalized) = match conf.endpoint {
+        OAuth2Endpoint::Token => normalize_token_response(status, upstream_body),
+        _ => {
+            if !status.is_success() || upstream_body.contains_key("error") {
+                let description = string_field(
+                    &upstream_body,
+                    &["error_description", "message", "msg", "error_msg", "errmsg"],
+                )
+                .unwrap_or("invalid access token");
+                return Ok(bearer_error(Some("invalid_token"), description));
+            }
+            (StatusCode::OK, Value::Object(upstream_body))
+        }
+    };
+    if !status.is_success() {
+        return Ok(json_response(status, &normalized));
+    }
+
+    // 响应阶段作用于规范化后的 json
+    let mut res_json_map = HashMap::new();
+    json_to_flat_map(&normalized, "", &mut res_json_map);
+    if let Some(claims) = &route.response.claims {
+        if let Err(e) = mapping::apply_claims(claims, &mut res_json_map) {
+            event!(Level::ERROR, "Missing required claim: {}", e.0);
+            return Ok(oauth_error(StatusCode::BAD_GATEWAY, "server_error", ""));
+        }
+    }
+    if let Err(e) = mapping::run_stage(
+        &route.response.mix_mappings,
+        route.response.script.as_ref(),
+        route.response.plugin.as_ref(),
+        "response",
+        &mut MappingParts {
+            headers: &mut res_headers,
+            query: None,
+            body: &mut res_json_map,
+            vars,
+        },
+    ) {
+        event!(Level::ERROR, "OAuth2 adapter response stage failed: {}", e);
+        return Ok(oauth_error(StatusCode::BAD_GATEWAY, "server_error", ""));
+    }
+    Ok(json_response(status, &flat_map_to_json(&res_json_map)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CircuitBreakerConfig, RetryConfig, UpstreamConfig};
+    use crate::{breaker, pool};
+    use axum::{
+        extract::{RawQuery, State},
+        routing::post,
+        Router,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // 本地 mock 非标准 SSO：参数放在 query，响应为 form
+    async fn mock_sso() -> String {
+        async fn token(RawQuery(query): RawQuery) -> (StatusCode, String) {
+            let q = query_to_multimap(&query.unwrap_or_default());
+            let get = |k: &str| {
+                q.get(k)
+                    .and_then(|v| v.first())
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            if get("client_id") != "app" || get("client_secret") != "s3:cr" {
+                return (
+                    StatusCode::OK,
+                    r#"{"code":401,"msg":"bad client"}"#.to_string(),
+                );
+            }
+            if get("code") != "abc" || get("code_verifier") != "v1" {
+                return (
+                    StatusCode::OK,
+                    r#"{"code":400,"msg":"invalid code"}"#.to_string(),
+                );
+            }
+            (
+                StatusCode::OK,
+                "access_token=t1&token_type=bearer&expires_in=3600".to_string(),
+            )
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/sso/oauth/accessToken", post(token));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn route(endpoint: &str) -> PathConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+request:
+  target_service: !oauth2_adapter
+    endpoint: {}
+    upstream_path: /sso/oauth/accessToken
+    params_in: query
+  mix_mappings: []
+response:
+  mix_mappings: []
+"#,
+            endpoint
+        ))
+        .unwrap()
+    }
+
+    async fn call(route: &PathConfig, headers: HeaderMap, body: &str) -> (StatusCode, Value) {
+        let base = mock_sso().await;
+        send(route, &base, UpstreamCall::default(), headers, body).await
+    }
+
+    async fn send(
+        route: &PathConfig,
+        base: &str,
+        upstream_call: UpstreamCall<'_>,
+        headers: HeaderMap,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let crate::config::ServiceType::OAuth2Adapter(conf) = &route.request.target_service else {
+            unreachable!()
+        };
+        let response = handle(
+            conf,
+            route,
+            Client::new(),
+            upstream_call,
+            base,
+            "/oauth/token",
+            &Method::POST,
+            &headers,
+            None,
+            &Bytes::from(body.to_string()),
+            &mut HashMap::new(),
+        )
+        .await;
+        let response = match response {
+            Ok(response) => response,
+            Err((status, message)) => return (status, Value::from(message)),
+        };
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn token_with_basic_auth_and_pkce() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        // app:s3%3Acr
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("app:s3%3Acr"));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&basic).unwrap(),
+        );
+        let (status, body) = call(
+            &route("token"),
+            headers.clone(),
+            "grant_type=authorization_code&code=abc&code_verifier=v1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"access_token": "t1", "token_type": "Bearer", "expires_in": 3600})
+        );
+
+        let (status, body) = call(
+            &route("token"),
+            headers,
+            "grant_type=authorization_code&code=bad&code_verifier=v1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({"error": "invalid_grant", "error_description": "invalid code"})
+        );
+    }
+
+    #[tokio::test]
+    async fn token_with_bad_client_is_invalid_client() {
+        // 上游返回 200 和 {"code":401}
+        let (status, body) = call(
+            &route("token"),
+            HeaderMap::new(),
+            "grant_type=authorization_code&code=abc&code_verifier=v1&client_id=app&client_secret=bad",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            body,
+            json!({"error": "invalid_client", "error_description": "bad client"})
+        );
+        let (status, _) = normalize_token_response(
+            StatusCode::OK,
+            serde_json::from_str(r#"{"errcode":"401"}"#).unwrap(),
+        );
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn retry_sso_unavailable() {
+        // 第一次返回 503，之后返回 token
+        async fn flaky(State(calls): State<Arc<AtomicUsize>>) -> (StatusCode, &'static str) {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                (StatusCode::SERVICE_UNAVAILABLE, "")
+            } else {
+                (StatusCode::OK, r#"{"access_token":"t1","expires_in":"60"}"#)
+            }
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/sso/oauth/accessToken", post(flaky))
+            .with_state(calls.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let retry: RetryConfig =
+            serde_yaml::from_str("{initial_backoff_ms: 1, retry_post: true}").unwrap();
+        let upstream_call = UpstreamCall {
+            retry: Some(&retry),
+            ..Default::default()
+        };
+        let (status, body) = send(
+            &route("token"),
+            &base,
+            upstream_call,
+            HeaderMap::new(),
+            "grant_type=client_credentials",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["access_token"], "t1");
+        assert_eq!(body["expires_in"], 60);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn unreachable_sso_opens_breaker_and_ejects_target() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let conf: CircuitBreakerConfig =
+            serde_yaml::from_str("{consecutive_failures: 1}").unwrap();
+        let upstream: UpstreamConfig = serde_yaml::from_str(&format!(
+            "{{targets: ['{}'], ejection: {{consecutive_failures: 1}}}}",
+            base
+        ))
+        .unwrap();
+        let target = pool::pick("oauth2-pool", &upstream, None).unwrap();
+        let upstream_call = UpstreamCall {
+            breaker: Some((breaker::acquire("oauth2-sso", &conf).unwrap(), &conf)),
+            target: Some((&target, &upstream.ejection)),
+            ..Default::default()
+        };
+        let (status, _) = send(
+            &route("token"),
+            &target.url,
+            upstream_call,
+            HeaderMap::new(),
+            "grant_type=client_credentials",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(breaker::status("oauth2-sso")["state"], "open");
+        assert_eq!(pool::status("oauth2-pool").unwrap()[0]["ejections_total"], 1);
+    }
+
+    #[tokio::test]
+    async fn token_rejects_missing_grant_and_double_auth() {
+        let mut headers = HeaderMap::new();
+        let (status, body) = call(&route("token"), headers.clone(), "code=abc").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_request");
+
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("app:x"));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&basic).unwrap(),
+        );
+        let (status, body) = call(
+            &route("token"),
+            headers,
+            "grant_type=authorization_code&code=abc&client_id=app&client_secret=x",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["error_description"],
+            "multiple client authentication methods"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+// 合成 OIDC discovery 文档和 JWKS，端点指向本服务的 oauth2_adapter 路由
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use tracing::{event, Level};
+
+use crate::cache::FileCache;
+use crate::config::{AppConfig, JwkFile, OAuth2Endpoint, OidcConfig, PathConfig, ServiceType};
+
+pub const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+// jwk 中的私钥字段，输出前移除
+const PRIVATE_MEMBERS: [&str; 7] = ["d", "p", "q", "dp", "dq", "qi", "k"];
+
+fn issuer(conf: &OidcConfig, app_config: &AppConfig) -> String {
+    let issuer = match &conf.issuer {
+        Some(issuer) => issuer.clone(),
+        None if app_config.self_host.contains("://") => app_config.self_host.clone(),
+        None => format!("https://{}", app_config.self_host),
+    };
+    issuer.trim_end_matches('/').to_string()
+}
+
+// 配置的路径优先，否则查找对应端点的 oauth2_adapter 路由
+fn endpoint_path(
+    configured: Option<&String>,
+    path_configs: &HashMap<String, PathConfig>,
+    endpoint: OAuth2Endpoint,
+) -> Option<String> {
+    if let Some(path) = configured {
+        return Some(path.clone());
+    }
+    let mut paths: Vec<&String> = path_configs
+        .iter()
+        .filter(|(_, c)| {
+            matches!(&c.request.target_service, ServiceType::OAuth2Adapter(a) if a.endpoint == endpoint)
+        })
+        .map(|(path, _)| path)
+        .collect();
+    paths.sort();
+    paths.first().map(|p| p.to_string())
+}
+
+/// 合成 discovery 文档
+pub fn discovery_document(
+    conf: &OidcConfig,
+    app_config: &AppConfig,
+    path_configs: &HashMap<String, PathConfig>,
+) -> Value {
+    let issuer = issuer(conf, app_config);
+    let url = |path: Option<String>| path.map(|p| format!("{}{}", issuer, p));
+    let mut algs: BTreeSet<String> = conf.jwks.iter().filter_map(|k| k.alg.clone()).collect();
+    if algs.is_empty() {
+        algs.insert("RS256".to_string());
+    }
+    let mut doc = json!({
+        "issuer": issuer,
+        "authorization_endpoint": url(endpoint_path(conf.authorization_path.as_ref(), path_configs, OAuth2Endpoint::Authorize)),
+        "token_endpoint": url(endpoint_path(conf.token_path.as_ref(), path_configs, OAuth2Endpoint::Token)),
+        "userinfo_endpoint": url(endpoint_path(conf.userinfo_path.as_ref(), path_configs, OAuth2Endpoint::Userinfo)),
+        "jwks_uri": format!("{}{}", issuer, conf.jwks_path),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": algs,
+        "scopes_supported": conf.scopes_supported,
+        "grant_types_supported": conf.grant_types_supported,
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        "code_challenge_methods_supported": conf.pkce.then_some(["S256"]),
+    });
+    let Value::Object(map) = &mut doc else {
+        unreachable!()
+    };
+    map.retain(|_, v| !v.is_null());
+    if let Some(extra) = &conf.extra {
+        map.extend(extra.clone());
+    }
+    doc
+}
+
+fn b64(bytes: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// pem 公钥（或私钥对应的公钥）转为 jwk
+fn pem_to_jwk(pem: &str) -> anyhow::Result<Value> {
+    let rsa = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem).map(|k| k.to_public_key()))
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem).map(|k| k.to_public_key()));
+    if let Ok(key) = rsa {
+        return Ok(json!({
+            "kty": "RSA",
+            "n": b64(&key.n().to_bytes_be()),
+            "e": b64(&key.e().to_bytes_be()),
+            "alg": "RS256",
+        }));
+    }
+    let ec = p256::PublicKey::from_public_key_pem(pem)
+        .or_else(|_| p256::SecretKey::from_pkcs8_pem(pem).map(|k| k.public_key()));
+    if let Ok(key) = ec {
+        let mut jwk: Value = serde_json::from_str(&key.to_jwk_string())?;
+        jwk["alg"] = Value::from("ES256");
+        return Ok(jwk);
+    }
+    anyhow::bail!("unsupported pem key, expect RSA or P-256")
+}
+
+// 解析后的密钥文件，pem 没有自带 kid
+struct ParsedKeys {
+    pem: bool,
+    keys: Vec<Value>,
+}
+
+static KEY_FILES: FileCache<ParsedKeys> = FileCache::new();
+
+fn parse_file(path: &Path) -> anyhow::Result<ParsedKeys> {
+    let content = std::fs::read_to_string(path)?;
+    if content.trim_start().starts_with("-----BEGIN") {
+        return Ok(ParsedKeys {
+            pem: true,
+            keys: vec![pem_to_jwk(&content)?],
+        });
+    }
+    let keys = match serde_json::from_str::<Value>(&content)? {
+        Value::Object(mut jwks) if jwks.contains_key("keys") => match jwks.remove("keys") {
+            Some(Value::Array(keys)) => keys,
+            _ => anyhow::bail!("keys must be an array"),
+        },
+        jwk @ Value::Object(_) => vec![jwk],
+        _ => anyhow::bail!("jwk file must be a json object"),
+    };
+    Ok(ParsedKeys { pem: false, keys })
+}
+
+fn load_file(conf: &JwkFile) -> anyhow::Result<Vec<Value>> {
+    let parsed = KEY_FILES.get(&conf.file, parse_file)?;
+    let mut keys = parsed.keys.clone();
+    if parsed.pem {
+        let kid = conf.kid.clone().unwrap_or_else(|| {
+            Path::new(&conf.file)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        keys[0]["kid"] = Value::from(kid);
+    }
+    for jwk in keys.iter_mut() {
+        if let Value::Object(map) = jwk {
+            for member in PRIVATE_MEMBERS {
+                map.remove(member);
+            }
+            map.entry("use").or_insert_with(|| Value::from("sig"));
+            if let Some(alg) = &conf.alg {
+                map.insert("alg".to_string(), Value::from(alg.clone()));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// 从配置的文件加载 JWKS
+pub fn load_jwks(files: &[JwkFile]) -> anyhow::Result<Value> {
+    let mut keys = Vec::new();
+    for file in files {
+        keys.extend(
+            load_file(file).map_err(|e| anyhow::anyhow!("load jwk {} failed: {}", file.file, e))?,
+        );
+    }
+    Ok(json!({ "keys": keys }))
+}
+
+/// discovery 或 jwks 路径时返回响应
+pub fn handle(
+    path: &str,
+    conf: &OidcConfig,
+    app_config: &AppConfig,
+    path_configs: &HashMap<String, PathConfig>,
+) -> Option<Response> {
+    let body = if path == DISCOVERY_PATH {
+        discovery_document(conf, app_config, path_configs)
+    } else if path == conf.jwks_path {
+        match load_jwks(&conf.jwks) {
+            Ok(jwks) => jwks,
+            Err(e) => {
+                event!(Level::ERROR, "{}", e);
+                return Some((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response());
+            }
+        }
+    } else {
+        return None;
+    };
+    Some(
+        (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "application/json"),
+                (header::CACHE_CONTROL, "public, max-age=300"),
+            ],
+            body.to_string(),
+        )
+            .into_response(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{parse_mapping, UseMode};
+
+    const RSA_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDHmXe/BeusEyuXmBExA9BFYRDN
+GjCk4eS+tz+xvodDfQpu8bJr7utaSR4duWGQaKIaINwQLDyWl+anbaVW8q6AQpNZ
+reUrwToojm0OItbMOYzhlqK5Rie1RBmqKiBLTH72UQBaVP5BEXOJKWDqg1TVRSwq
+62ugjC3Ytfl9wLfM4wIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    const EC_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE9JCVp4syf4MQKkoHogJW72zZhLN0
+1YrS3dVDR6lyyjY0GJtq2RHaCkcxYfqyIYEBBdVKj3ZTQ0tObIrc/oBYJg==
+-----END PUBLIC KEY-----
+";
+
+    fn app_config() -> AppConfig {
+        AppConfig {
+            dify_url: String::new(),
+            sso_url: None,
+            config_path: String::new(),
+            use_mode: UseMode::Normal,
+            dify_host: None,
+            self_host: "auth.apps.dify.com".to_string(),
+            max_body_size: 0,
+        }
+    }
+
+    #[test]
+    fn discovery_points_at_adapter_routes() {
+        let (paths, global) = parse_mapping(
+            r#"
+oidc:
+  extra:
+    claims_supported: [sub, email]
+"/oauth2/token":
+  request:
+    target_service: !oauth2_adapter
+      endpoint: token
+    mix_mappings: []
+  response:
+    mix_mappings: []
+"/oauth2/authorize":
+  request:
+    target_service: !oauth2_adapter
+      endpoint: authorize
+    mix_mappings: []
+  response:
+    mix_mappings: []
+"#,
+        )
+        .unwrap();
+        let doc = discovery_document(&global.oidc.unwrap(), &app_config(), &paths);
+        assert_eq!(doc["issuer"], "https://auth.apps.dify.com");
+        assert_eq!(
+            doc["token_endpoint"],
+            "https://auth.apps.dify.com/oauth2/token"
+        );
+        assert_eq!(
+            doc["authorization_endpoint"],
+            "https://auth.apps.dify.com/oauth2/authorize"
+        );
+        assert!(doc.get("userinfo_endpoint").is_none());
+        assert_eq!(
+            doc["jwks_uri"],
+            "https://auth.apps.dify.com/.well-known/jwks.json"
+        );
+        assert_eq!(doc["claims_supported"], json!(["sub", "email"]));
+        assert_eq!(doc["grant_types_supported"], json!(["authorization_code"]));
+        assert!(doc.get("code_challenge_methods_supported").is_none());
+
+        // 按配置声明 grant_type 和 PKCE，算法去重
+        let conf: OidcConfig = serde_yaml::from_str(
+            r#"
+grant_types_supported: [authorization_code, refresh_token]
+pkce: true
+jwks:
+- {file: a.pem, alg: RS256}
+- {file: b.pem, alg: ES256}
+- {file: c.pem, alg: RS256}
+"#,
+        )
+        .unwrap();
+        let doc = discovery_document(&conf, &app_config(), &paths);
+        assert_eq!(
+            doc["grant_types_supported"],
+            json!(["authorization_code", "refresh_token"])
+        );
+        assert_eq!(doc["code_challenge_methods_supported"], json!(["S256"]));
+        assert_eq!(
+            doc["id_token_signing_alg_values_supported"],
+            json!(["ES256", "RS256"])
+        );
+    }
+
+    #[test]
+    fn jwks_from_pem_files() {
+        let dir = std::env::temp_dir().join(format!("oidc-jwks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rsa = dir.join("sso-rsa.pem");
+        let ec = dir.join("sso-ec.pem");
+        std::fs::write(&rsa, RSA_PEM).unwrap();
+        std::fs::write(&ec, EC_PEM).unwrap();
+        let jwks = load_jwks(&[
+            JwkFile {
+                file: rsa.to_string_lossy().into_owned(),
+                kid: None,
+                alg: None,
+            },
+            JwkFile {
+                file: ec.to_string_lossy().into_owned(),
+                kid: Some("ec-1".to_string()),
+                alg: None,
+            },
+        ])
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let keys = jwks["keys"].as_array().unwrap();
+        assert_eq!(keys[0]["kty"], "RSA");
+        assert_eq!(keys[0]["kid"], "sso-rsa");
+        assert_eq!(keys[0]["e"], "AQAB");
+        assert!(keys[0]["n"].as_str().unwrap().starts_with("x5l3vwXr"));
+        assert_eq!(keys[1]["kty"], "EC");
+        assert_eq!(keys[1]["crv"], "P-256");
+        assert_eq!(keys[1]["kid"], "ec-1");
+        assert_eq!(keys[1]["use"], "sig");
+    }
+}
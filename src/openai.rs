@@ -0,0 +1,543 @@
+// OpenAI `/v1/chat/completions` 协议到 Dify `/v1/chat-messages` 的适配
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use tracing::{event, Level};
+
+use crate::config::{OpenAiAdapterConfig, SseConfig};
+use crate::sse::{self, SseEvent, SseParser};
+
+const CONVERSATION_HEADER: &str = "x-dify-conversation-id";
+const LAST_EVENT_ID: &str = "last-event-id";
+
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(s) => s.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter(|p| p["type"] == "text")
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// OpenAI 请求转为 Dify chat-messages 请求
+pub fn to_dify_request(
+    conf: &OpenAiAdapterConfig,
+    req: &Value,
+    conversation_id: Option<&str>,
+) -> Result<Value, String> {
+    let messages = req["messages"]
+        .as_array()
+        .ok_or("messages must be an array")?;
+    let last_user = messages
+        .iter()
+        .rposition(|m| m["role"] == "user")
+        .ok_or("messages must contain a user message")?;
+
+    let mut query = text_of(&messages[last_user]["content"]);
+    if conf.include_history {
+        let history: Vec<String> = messages[..last_user]
+            .iter()
+            .filter(|m| m["role"] != "system")
+            .map(|m| {
+                format!(
+                    "{}: {}",
+                    m["role"].as_str().unwrap_or_default(),
+                    text_of(&m["content"])
+                )
+            })
+            .collect();
+        if !history.is_empty() {
+            query = format!("{}\nuser: {}", history.join("\n"), query);
+        }
+    }
+
+    let mut inputs = req["inputs"].as_object().cloned().unwrap_or_default();
+    if let Some(key) = &conf.system_input {
+        let system: Vec<String> = messages
+            .iter()
+            .filter(|m| m["role"] == "system")
+            .map(|m| text_of(&m["content"]))
+            .collect();
+        if !system.is_empty() {
+            inputs.insert(key.clone(), Value::from(system.join("\n")));
+        }
+    }
+
+    // 图片以 remote_url 方式传给 Dify
+    let files: Vec<Value> = messages[last_user]["content"]
+        .as_array()
+        .map(|parts| {
+            parts
+                .iter()
+                .filter(|p| p["type"] == "image_url")
+                .filter_map(|p| p["image_url"]["url"].as_str())
+                .map(|url| json!({"type": "image", "transfer_method": "remote_url", "url": url}))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let stream = req["stream"].as_bool().unwrap_or(false);
+    Ok(json!({
+        "inputs": inputs,
+        "query": query,
+        "response_mode": if stream { "streaming" } else { "blocking" },
+        "conversation_id": conversation_id
+            .or(req["conversation_id"].as_str())
+            .unwrap_or_default(),
+        "user": req["user"].as_str().unwrap_or(&conf.default_user),
+        "files": files,
+    }))
+}
+
+fn usage_of(metadata: &Value) -> Value {
+    let usage = &metadata["usage"];
+    json!({
+        "prompt_tokens": usage["prompt_tokens"].as_u64().unwrap_or(0),
+        "completion_tokens": usage["completion_tokens"].as_u64().unwrap_or(0),
+        "total_tokens": usage["total_tokens"].as_u64().unwrap_or(0),
+    })
+}
+
+/// Dify blocking 响应转为 chat.completion
+pub fn to_openai_completion(model: &str, res: &Value) -> Value {
+    json!({
+        "id": format!("chatcmpl-{}", res["message_id"].as_str().unwrap_or_default()),
+        "object": "chat.completion",
+        "created": res["created_at"].as_u64().unwrap_or(0),
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": res["answer"].as_str().unwrap_or_default()},
+            "finish_reason": "stop",
+        }],
+        "usage": usage_of(&res["metadata"]),
+    })
+}
+
+/// 错误转为 OpenAI 错误结构
+pub fn to_openai_error(status: StatusCode, err: &Value) -> Value {
+    let error_type = match status.as_u16() {
+        401 | 403 => "authentication_error",
+        429 => "rate_limit_error",
+        400..=499 => "invalid_request_error",
+        _ => "api_error",
+    };
+    json!({
+        "error": {
+            "message": err["message"].as_str().map(String::from).unwrap_or_else(|| err.to_string()),
+            "type": error_type,
+            "param": null,
+            "code": err["code"].clone(),
+        }
+    })
+}
+
+fn error_response(status: StatusCode, err: &Value) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, mime::APPLICATION_JSON.to_string())],
+        to_openai_error(status, err).to_string(),
+    )
+        .into_response()
+}
+
+/// 流式转换状态
+pub struct ChunkTranslator {
+    model: String,
+    include_usage: bool,
+    id: String,
+    conversation_id: String,
+    created: u64,
+    role_sent: bool,
+    finished: bool,
+}
+
+impl ChunkTranslator {
+    pub fn new(model: &str, include_usage: bool) -> Self {
+        ChunkTranslator {
+            model: model.to_string(),
+            include_usage,
+            id: String::new(),
+            conversation_id: String::new(),
+            created: 0,
+            role_sent: false,
+            finished: false,
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Value) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    }
+
+    /// Dify 事件转为 OpenAI chunk 帧，忽略 ping / workflow 等事件
+    pub fn translate(&mut self, event: &SseEvent) -> Vec<String> {
+        let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+            return vec![];
+        };
+        if self.id.is_empty() {
+            if let Some(id) = data["message_id"].as_str() {
+                self.id = format!("chatcmpl-{}", id);
+            }
+            self.created = data["created_at"].as_u64().unwrap_or(0);
+        }
+        if self.conversation_id.is_empty() {
+            if let Some(id) = data["conversation_id"].as_str() {
+                self.conversation_id = id.to_string();
+            }
+        }
+        match data["event"].as_str().unwrap_or_default() {
+            "message" | "agent_message" => {
+                let mut delta = json!({"content": data["answer"].as_str().unwrap_or_default()});
+                if self.role_sent {
+                    return vec![frame(&self.chunk(delta, Value::Null))];
+                }
+                // 第一个 chunk 带上会话 id，客户端用于继续对话
+                self.role_sent = true;
+                delta["role"] = Value::from("assistant");
+                let mut chunk = self.chunk(delta, Value::Null);
+                if !self.conversation_id.is_empty() {
+                    chunk["conversation_id"] = Value::from(self.conversation_id.clone());
+                }
+                vec![frame(&chunk)]
+            }
+            "message_end" => {
+                self.finished = true;
+                let usage = usage_of(&data["metadata"]);
+                event!(Level::INFO, "OpenAI facade usage: {}", usage);
+                let mut frames = vec![frame(&self.chunk(json!({}), Value::from("stop")))];
+                if self.include_usage {
+                    frames.push(frame(&json!({
+                        "id": self.id,
+                        "object": "chat.completion.chunk",
+                        "created": self.created,
+                        "model": self.model,
+                        "choices": [],
+                        "usage": usage,
+                    })));
+                }
+                frames.push(done());
+                frames
+            }
+            "error" => {
+                self.finished = true;
+                let status = data["status"]
+                    .as_u64()
+                    .and_then(|s| StatusCode::from_u16(s as u16).ok())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                vec![frame(&to_openai_error(status, &data)), done()]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// 上游未正常结束时补齐结束帧
+    pub fn finish(&mut self) -> Vec<String> {
+        if self.finished {
+            return vec![];
+        }
+        self.finished = true;
+        vec![frame(&self.chunk(json!({}), Value::from("stop"))), done()]
+    }
+}
+
+fn frame(value: &Value) -> String {
+    SseEvent {
+        data: value.to_string(),
+        ..Default::default()
+    }
+    .to_frame()
+}
+
+fn done() -> String {
+    "data: [DONE]\n\n".to_string()
+}
+
+/// 转换后的 Dify 请求
+pub struct Prepared {
+    request: Value,
+    model: String,
+    include_usage: bool,
+}
+
+/// 请求无效时的 OpenAI 格式错误响应
+pub fn invalid_request(message: &str) -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        &json!({"message": message, "code": "invalid_request"}),
+    )
+}
+
+/// OpenAI 请求转为 Dify 请求，请求无效时返回错误信息
+pub fn prepare(
+    conf: &OpenAiAdapterConfig,
+    headers: &HeaderMap,
+    req: Value,
+) -> Result<Prepared, String> {
+    let conversation_id = headers
+        .get(CONVERSATION_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let request = to_dify_request(conf, &req, conversation_id)?;
+    let model = conf
+        .model
+        .clone()
+        .or_else(|| req["model"].as_str().map(String::from))
+        .unwrap_or_else(|| "dify".to_string());
+    let include_usage = req["stream_options"]["include_usage"]
+        .as_bool()
+        .unwrap_or(false);
+    event!(Level::DEBUG, "OpenAI facade request: {}", request);
+    Ok(Prepared {
+        request,
+        model,
+        include_usage,
+    })
+}
+
+impl Prepared {
+    /// 构造上游请求，headers 为请求阶段处理后的 header（含 Authorization）
+    pub fn build(
+        &self,
+        conf: &OpenAiAdapterConfig,
+        client: &reqwest::Client,
+        base_url: &str,
+        headers: &HeaderMap,
+    ) -> reqwest::RequestBuilder {
+        let mut request = client
+            .post(format!("{}{}", base_url, conf.chat_path))
+            .json(&self.request);
+        if let Some(auth) = headers.get(header::AUTHORIZATION) {
+            request = request.header(header::AUTHORIZATION, auth);
+        }
+        // 断线重连时携带最后收到的事件 id
+        if let Some(last_event_id) = headers.get(LAST_EVENT_ID) {
+            request = request.header(LAST_EVENT_ID, last_event_id);
+        }
+        request
+    }
+}
+
+/// Dify 响应转为 OpenAI 响应
+pub async fn respond(
+    prepared: Prepared,
+    sse_conf: &SseConfig,
+    response: reqwest::Response,
+) -> Result<Response, (StatusCode, String)> {
+    let Prepared {
+        request: dify_req,
+        model,
+        include_usage,
+    } = prepared;
+    let stream = dify_req["response_mode"] == "streaming";
+    let status = response.status();
+
+    if !status.is_success() {
+        let body = response.bytes().await.unwrap_or_default();
+        let err = serde_json::from_slice::<Value>(&body)
+            .unwrap_or_else(|_| json!({"message": String::from_utf8_lossy(&body)}));
+        return Ok(error_response(status, &err));
+    }
+
+    if !stream {
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Body read failed: {}", e)))?;
+        let res: Value = serde_json::from_slice(&body)
+            .map_err(|e| (StatusCode::BAD_GATEWAY, format!("JSON parse error: {}", e)))?;
+        let mut res_headers = HeaderMap::new();
+        res_headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        if let Some(Ok(cid)) = res["conversation_id"].as_str().map(HeaderValue::from_str) {
+            res_headers.insert(CONVERSATION_HEADER, cid);
+        }
+        let completion = to_openai_completion(&model, &res);
+        event!(Level::INFO, "OpenAI facade usage: {}", completion["usage"]);
+        return Ok((status, res_headers, completion.to_string()).into_response());
+    }
+
+    let upstream = response.bytes_stream();
+    let stream = async_stream::stream! {
+        let mut parser = SseParser::default();
+        let mut translator = ChunkTranslator::new(&model, include_usage);
+        let mut upstream = std::pin::pin!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    event!(Level::ERROR, "OpenAI facade upstream error: {}", e);
+                    let err = to_openai_error(
+                        StatusCode::BAD_GATEWAY,
+                        &json!({"message": e.to_string()}),
+                    );
+                    yield Ok::<Vec<u8>, std::io::Error>(frame(&err).into_bytes());
+                    break;
+                }
+            };
+            for event in parser.push(&chunk) {
+                for out in translator.translate(&event) {
+                    yield Ok(out.into_bytes());
+                }
+            }
+        }
+        for out in translator.finish() {
+            yield Ok(out.into_bytes());
+        }
+    };
+
+    // 继续已有会话时 id 已知，同时通过 header 返回
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/event-stream"),
+    );
+    if let Some(Ok(cid)) = dify_req["conversation_id"]
+        .as_str()
+        .filter(|cid| !cid.is_empty())
+        .map(HeaderValue::from_str)
+    {
+        res_headers.insert(CONVERSATION_HEADER, cid);
+    }
+    Ok((
+        status,
+        res_headers,
+        Body::from_stream(sse::keepalive(stream, sse_conf)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+
+    fn conf() -> OpenAiAdapterConfig {
+        serde_yaml::from_str("{}").unwrap()
+    }
+
+    // 本地 mock Dify
+    async fn mock_dify() -> String {
+        async fn chat(Json(req): Json<Value>) -> Response {
+            if req["query"] == "fail" {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"code": "invalid_param", "message": "bad query", "status": 400})),
+                )
+                    .into_response();
+            }
+            if req["response_mode"] == "blocking" {
+                return Json(json!({
+                    "event": "message", "message_id": "m1", "conversation_id": "c1",
+                    "answer": format!("echo {}", req["query"].as_str().unwrap()),
+                    "created_at": 1700000000,
+                    "metadata": {"usage": {"prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5}},
+                }))
+                .into_response();
+            }
+            let body = concat!(
+                "data: {\"event\":\"message\",\"message_id\":\"m1\",\"conversation_id\":\"c1\",\"answer\":\"He\",\"created_at\":1}\n\n",
+                "event: ping\n\n",
+                "data: {\"event\":\"message\",\"message_id\":\"m1\",\"answer\":\"llo\",\"created_at\":1}\n\n",
+                "data: {\"event\":\"message_end\",\"message_id\":\"m1\",\"metadata\":{\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2,\"total_tokens\":5}}}\n\n",
+            );
+            ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/v1/chat-messages", post(chat));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn call(req: Value) -> (StatusCode, String) {
+        let base = mock_dify().await;
+        let headers = HeaderMap::new();
+        let response = match prepare(&conf(), &headers, req) {
+            Ok(prepared) => {
+                let upstream = prepared
+                    .build(&conf(), &reqwest::Client::new(), &base, &headers)
+                    .send()
+                    .await
+                    .unwrap();
+                respond(prepared, &SseConfig::default(), upstream)
+                    .await
+                    .unwrap()
+            }
+            Err(message) => invalid_request(&message),
+        };
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn blocking_completion() {
+        let (status, body) = call(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "system", "content": "be nice"}, {"role": "user", "content": "hi"}],
+        }))
+        .await;
+        let res: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(res["choices"][0]["message"]["content"], "echo hi");
+        assert_eq!(res["usage"]["total_tokens"], 5);
+        assert_eq!(res["model"], "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn streaming_completion() {
+        let (_, body) = call(json!({
+            "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }))
+        .await;
+        let mut parser = SseParser::default();
+        let events: Vec<String> = parser
+            .push(body.as_bytes())
+            .into_iter()
+            .map(|e| e.data)
+            .collect();
+        let chunks: Vec<Value> = events
+            .iter()
+            .filter(|d| *d != "[DONE]")
+            .map(|d| serde_json::from_str(d).unwrap())
+            .collect();
+        assert_eq!(
+            chunks[0]["choices"][0]["delta"],
+            json!({"role": "assistant", "content": "He"})
+        );
+        assert_eq!(chunks[0]["conversation_id"], "c1");
+        assert_eq!(chunks[1]["choices"][0]["delta"], json!({"content": "llo"}));
+        assert!(chunks[1].get("conversation_id").is_none());
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[3]["usage"]["completion_tokens"], 2);
+        assert_eq!(events.last().unwrap(), "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn error_shape() {
+        let (status, body) = call(json!({"messages": [{"role": "user", "content": "fail"}]})).await;
+        let res: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["error"]["type"], "invalid_request_error");
+        assert_eq!(res["error"]["message"], "bad query");
+    }
+}
@@ -0,0 +1,220 @@
+// wasm 插件 ABI：
+// - 导出 `memory` 和 `alloc(len: i32) -> i32`
+// - 导出处理函数（默认 `transform`）`fn(ptr: i32, len: i32) -> i64`，
+//   输入输出均为 utf-8 json，返回值高 32 位为输出指针，低 32 位为输出长度
+// 转换输入 {"value": ..., "dst": ...}，输出 {"value": ...}；
+// 阶段钩子输入 {"stage": ..., "headers": {}, "query": {}, "body": {}, "vars": {}}，
+// 输出中出现的部分替换原值
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::{event, Level};
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+use crate::cache::FileCache;
+use crate::config::PluginConfig;
+use crate::mapping::{apply_parts_json, parts_to_json, MappingParts};
+
+// 所有插件共用的引擎，开启 fuel 计量
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    })
+}
+
+// 编译后的模块按路径和修改时间缓存，每次调用使用新的实例
+static MODULES: FileCache<Module> = FileCache::new();
+
+fn load_module(path: &Path) -> anyhow::Result<Module> {
+    let bytes = std::fs::read(path)?;
+    Ok(Module::new(engine(), &bytes[..])?)
+}
+
+fn call_plugin(conf: &PluginConfig, module: &Module, input: &Value) -> anyhow::Result<Value> {
+    let engine = engine();
+    let mut store = Store::new(engine, ());
+    store
+        .set_fuel(conf.fuel)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let linker = <Linker<()>>::new(engine);
+    let instance = linker.instantiate(&mut store, module)?.start(&mut store)?;
+    let memory = instance
+        .get_memory(&store, "memory")
+        .ok_or_else(|| anyhow::anyhow!("plugin does not export memory"))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+    let handler = instance.get_typed_func::<(i32, i32), i64>(&store, &conf.function)?;
+
+    let input = serde_json::to_vec(input)?;
+    let len = i32::try_from(input.len())?;
+    let ptr = alloc.call(&mut store, len)?;
+    memory
+        .write(&mut store, ptr as u32 as usize, &input)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let packed = handler.call(&mut store, (ptr, len))?;
+    let out_ptr = (packed as u64 >> 32) as usize;
+    let out_len = (packed as u64 & 0xffff_ffff) as usize;
+    // 先校验输出在插件内存范围内，避免按插件返回的长度分配过大的内存
+    let output = out_ptr
+        .checked_add(out_len)
+        .and_then(|end| memory.data(&store).get(out_ptr..end))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "output {}+{} out of plugin memory ({} bytes)",
+                out_ptr,
+                out_len,
+                memory.data(&store).len()
+            )
+        })?;
+
+    Ok(serde_json::from_slice(output)?)
+}
+
+fn run(conf: &PluginConfig, input: &Value) -> anyhow::Result<Value> {
+    MODULES
+        .get(&conf.file, load_module)
+        .and_then(|module| call_plugin(conf, &module, input))
+        .map_err(|e| anyhow::anyhow!("plugin {} failed: {}", conf.file, e))
+}
+
+/// 执行阶段插件
+pub fn run_stage_plugin(
+    conf: &PluginConfig,
+    stage: &str,
+    parts: &mut MappingParts,
+) -> anyhow::Result<()> {
+    let mut input = parts_to_json(parts);
+    input["stage"] = Value::from(stage);
+    let output = run(conf, &input)?;
+    apply_parts_json(parts, output)
+}
+
+/// 插件转换，输出 value 为空时视为空结果
+pub fn run_value_plugin(
+    conf: &PluginConfig,
+    value: &Value,
+    dst_value: Option<&str>,
+) -> Option<Value> {
+    match run(conf, &json!({ "value": value, "dst": dst_value })) {
+        Ok(mut output) => match output["value"].take() {
+            Value::Null => None,
+            v => Some(v),
+        },
+        Err(e) => {
+            event!(Level::ERROR, "Plugin transformation failed: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 固定返回 {"value":"admin"} 的插件
+    const FIXED_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"value\":\"admin\"}")
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+            (i64.const 17))
+          (func (export "spin") (param $ptr i32) (param $len i32) (result i64)
+            (loop $l (br $l))
+            (i64.const 0))
+          (func (export "huge") (param $ptr i32) (param $len i32) (result i64)
+            (i64.const 0xffffffff)))
+    "#;
+
+    fn plugin(function: &str) -> PluginConfig {
+        PluginConfig {
+            file: String::new(),
+            function: function.to_string(),
+            fuel: 100_000,
+        }
+    }
+
+    // 示例插件需要 wasm32-unknown-unknown 目标，未构建时跳过
+    #[test]
+    fn sample_plugin_matches_abi() {
+        let file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/plugins/sample/target/wasm32-unknown-unknown/release/sample_plugin.wasm"
+        );
+        if !Path::new(file).exists() {
+            eprintln!("{} not built, skipped", file);
+            return;
+        }
+        let conf = PluginConfig {
+            file: file.to_string(),
+            function: "transform".to_string(),
+            fuel: 10_000_000,
+        };
+        assert_eq!(
+            run_value_plugin(&conf, &json!("id=Admin,id=TATTOO"), None),
+            Some(json!("admin"))
+        );
+
+        let mut headers = hyper::HeaderMap::new();
+        let mut query = std::collections::HashMap::from([
+            ("b".to_string(), vec!["2".to_string()]),
+            ("a".to_string(), vec!["1".to_string(), "3".to_string()]),
+        ]);
+        let mut parts = MappingParts {
+            headers: &mut headers,
+            query: Some(&mut query),
+            body: &mut Default::default(),
+            vars: &mut Default::default(),
+        };
+        let conf = PluginConfig {
+            function: "stage".to_string(),
+            ..conf
+        };
+        run_stage_plugin(&conf, "request", &mut parts).unwrap();
+        assert_eq!(headers["x-canonical-query"], "a=1&a=3&b=2");
+    }
+
+    fn fixed_plugin() -> Module {
+        let wasm = wat::parse_str(FIXED_PLUGIN).unwrap();
+        Module::new(engine(), &wasm[..]).unwrap()
+    }
+
+    #[test]
+    fn call_fixed_plugin() {
+        let output =
+            call_plugin(&plugin("transform"), &fixed_plugin(), &json!({"value": "x"})).unwrap();
+        assert_eq!(output, json!({"value": "admin"}));
+    }
+
+    #[test]
+    fn runaway_plugin_runs_out_of_fuel() {
+        assert!(call_plugin(&plugin("spin"), &fixed_plugin(), &json!({})).is_err());
+        // 输出长度超出插件内存时报错，不按该长度分配
+        let err = call_plugin(&plugin("huge"), &fixed_plugin(), &json!({})).unwrap_err();
+        assert!(err.to_string().contains("out of plugin memory"));
+    }
+
+    #[test]
+    fn module_loaded_once_from_file() {
+        let path = std::env::temp_dir().join(format!("plugin-{}.wasm", std::process::id()));
+        std::fs::write(&path, wat::parse_str(FIXED_PLUGIN).unwrap()).unwrap();
+        let conf = PluginConfig {
+            file: path.to_string_lossy().into_owned(),
+            ..plugin("transform")
+        };
+        assert_eq!(run_value_plugin(&conf, &json!("x"), None), Some(json!("admin")));
+        let first = MODULES.get(&conf.file, load_module).unwrap();
+        let second = MODULES.get(&conf.file, load_module).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&first, &second));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
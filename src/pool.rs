@@ -0,0 +1,471 @@
+// 上游地址池：负载均衡、主动健康检查和被动摘除，状态保存在内存中
+use futures_util::future::join_all;
+use hyper::{header, HeaderMap};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+use crate::config::{BalanceStrategy, EjectionConfig, HealthCheckConfig, UpstreamConfig};
+use crate::metrics::{Metrics, Sample};
+
+// 一致性哈希环上每个地址的虚拟节点数
+const VIRTUAL_NODES: usize = 100;
+
+struct Target {
+    url: String,
+    in_flight: Arc<AtomicUsize>,
+    // 主动健康检查的结果，未配置健康检查时总是健康
+    healthy: bool,
+    check_successes: u32,
+    check_failures: u32,
+    // 被动摘除
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+    ejections_total: u64,
+}
+
+impl Target {
+    fn new(url: &str) -> Self {
+        Target {
+            url: url.to_string(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            healthy: true,
+            check_successes: 0,
+            check_failures: 0,
+            consecutive_failures: 0,
+            ejected_until: None,
+            ejections_total: 0,
+        }
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        self.healthy && self.ejected_until.is_none_or(|until| until <= now)
+    }
+}
+
+struct Pool {
+    targets: Vec<Target>,
+    // 轮询的下一个位置
+    next: usize,
+    // 一致性哈希环：(哈希值, 地址下标)
+    ring: Vec<(u64, usize)>,
+    health_check: Option<HealthCheckConfig>,
+    checker_running: bool,
+}
+
+impl Pool {
+    fn new(urls: &[String]) -> Self {
+        let mut ring: Vec<(u64, usize)> = urls
+            .iter()
+            .enumerate()
+            .flat_map(|(i, url)| {
+                (0..VIRTUAL_NODES).map(move |n| (hash(&format!("{}#{}", url, n)), i))
+            })
+            .collect();
+        ring.sort_unstable();
+        Pool {
+            targets: urls.iter().map(|url| Target::new(url)).collect(),
+            next: 0,
+            ring,
+            health_check: None,
+            checker_running: false,
+        }
+    }
+}
+
+fn pools() -> &'static Mutex<HashMap<String, Pool>> {
+    static POOLS: OnceLock<Mutex<HashMap<String, Pool>>> = OnceLock::new();
+    POOLS.get_or_init(Default::default)
+}
+
+fn hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+fn cookie_get(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// 一致性哈希的 key：配置的 header 或 cookie，都读取不到时为客户端 IP
+pub fn hash_key(
+    balance: &BalanceStrategy,
+    headers: &HeaderMap,
+    client_ip: IpAddr,
+) -> Option<String> {
+    let BalanceStrategy::ConsistentHash { header, cookie } = balance else {
+        return None;
+    };
+    header
+        .as_ref()
+        .and_then(|name| headers.get(name.as_str()))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| cookie.as_ref().and_then(|name| cookie_get(headers, name)))
+        .or_else(|| Some(client_ip.to_string()))
+}
+
+/// 选中的地址，释放前计为该地址进行中的请求
+pub struct Selected {
+    name: String,
+    pub url: String,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for Selected {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Selected {
+    /// 记录请求结果，连续失败达到阈值时摘除该地址
+    pub fn record(
+        &self,
+        conf: &EjectionConfig,
+        result: &Result<reqwest::Response, reqwest::Error>,
+    ) {
+        let failed = match result {
+            Ok(response) => conf.failure_status.contains(&response.status().as_u16()),
+            Err(_) => true,
+        };
+        let mut pools = pools().lock().unwrap();
+        let Some(target) = pools
+            .get_mut(&self.name)
+            .and_then(|p| p.targets.iter_mut().find(|t| t.url == self.url))
+        else {
+            return;
+        };
+        if !failed {
+            target.consecutive_failures = 0;
+            return;
+        }
+        target.consecutive_failures += 1;
+        if conf.consecutive_failures > 0 && target.consecutive_failures >= conf.consecutive_failures
+        {
+            event!(
+                Level::WARN,
+                "Eject {} from upstream {} for {}s",
+                self.url,
+                self.name,
+                conf.eject_secs
+            );
+            target.consecutive_failures = 0;
+            target.ejected_until = Some(Instant::now() + Duration::from_secs(conf.eject_secs));
+            target.ejections_total += 1;
+        }
+    }
+}
+
+/// 按负载均衡策略选择地址，所有地址都不可用时在全部地址中选择；未配置 targets 时返回 None
+pub fn pick(name: &str, conf: &UpstreamConfig, key: Option<&str>) -> Option<Selected> {
+    if conf.targets.is_empty() {
+        return None;
+    }
+    let mut pools = pools().lock().unwrap();
+    // 地址变化时重建，保留仍存在的地址的状态
+    let pool = match pools.remove(name) {
+        Some(pool) if pool.targets.iter().map(|t| &t.url).eq(conf.targets.iter()) => pool,
+        previous => {
+            let mut pool = Pool::new(&conf.targets);
+            if let Some(previous) = previous {
+                pool.checker_running = previous.checker_running;
+                for old in previous.targets {
+                    if let Some(target) = pool.targets.iter_mut().find(|t| t.url == old.url) {
+                        *target = old;
+                    }
+                }
+            }
+            pool
+        }
+    };
+    let pool = pools.entry(name.to_string()).or_insert(pool);
+    pool.health_check = conf.health_check.clone();
+    if pool.health_check.is_some() && !pool.checker_running {
+        pool.checker_running = true;
+        tokio::spawn(run_health_checks(name.to_string()));
+    }
+
+    let now = Instant::now();
+    let len = pool.targets.len();
+    let mut available: Vec<bool> = pool.targets.iter().map(|t| t.available(now)).collect();
+    if !available.contains(&true) {
+        event!(
+            Level::WARN,
+            "No healthy target in upstream {}, use all targets",
+            name
+        );
+        available = vec![true; len];
+    }
+    let index = match (&conf.balance, key) {
+        (BalanceStrategy::ConsistentHash { .. }, Some(key)) => {
+            let key = hash(key);
+            let start = pool.ring.partition_point(|(h, _)| *h < key);
+            (0..pool.ring.len())
+                .map(|n| pool.ring[(start + n) % pool.ring.len()].1)
+                .find(|i| available[*i])?
+        }
+        (BalanceStrategy::LeastConnections, _) => (0..len)
+            .map(|n| (pool.next + n) % len)
+            .filter(|i| available[*i])
+            .min_by_key(|i| pool.targets[*i].in_flight.load(Ordering::SeqCst))?,
+        _ => (0..len)
+            .map(|n| (pool.next + n) % len)
+            .find(|i| available[*i])?,
+    };
+    pool.next = (index + 1) % len;
+    let target = &pool.targets[index];
+    target.in_flight.fetch_add(1, Ordering::SeqCst);
+    Some(Selected {
+        name: name.to_string(),
+        url: target.url.clone(),
+        in_flight: target.in_flight.clone(),
+    })
+}
+
+async fn check(client: &reqwest::Client, conf: &HealthCheckConfig, url: &str) -> bool {
+    let url = format!("{}{}", url.trim_end_matches('/'), conf.path);
+    match client
+        .get(&url)
+        .timeout(Duration::from_millis(conf.timeout_ms))
+        .send()
+        .await
+    {
+        Ok(response) if conf.expected_status.is_empty() => response.status().is_success(),
+        Ok(response) => conf.expected_status.contains(&response.status().as_u16()),
+        Err(e) => {
+            event!(Level::DEBUG, "Health check {} failed: {}", url, e);
+            false
+        }
+    }
+}
+
+// 定期检查上游的所有地址，配置中移除健康检查后退出
+async fn run_health_checks(name: String) {
+    let client = crate::build_client();
+    loop {
+        let (conf, urls) = {
+            let mut pools = pools().lock().unwrap();
+            let Some(pool) = pools.get_mut(&name) else {
+                return;
+            };
+            let Some(conf) = pool.health_check.clone() else {
+                pool.checker_running = false;
+                return;
+            };
+            (
+                conf,
+                pool.targets
+                    .iter()
+                    .map(|t| t.url.clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let results = join_all(urls.iter().map(|url| check(&client, &conf, url))).await;
+        {
+            let mut pools = pools().lock().unwrap();
+            let Some(pool) = pools.get_mut(&name) else {
+                return;
+            };
+            for (url, ok) in urls.iter().zip(results) {
+                let Some(target) = pool.targets.iter_mut().find(|t| &t.url == url) else {
+                    continue;
+                };
+                if ok {
+                    target.check_failures = 0;
+                    target.check_successes += 1;
+                    if !target.healthy && target.check_successes >= conf.healthy_threshold {
+                        event!(
+                            Level::INFO,
+                            "Target {} of upstream {} is healthy",
+                            url,
+                            name
+                        );
+                        target.healthy = true;
+                    }
+                } else {
+                    target.check_successes = 0;
+                    target.check_failures += 1;
+                    if target.healthy && target.check_failures >= conf.unhealthy_threshold {
+                        event!(
+                            Level::WARN,
+                            "Target {} of upstream {} is unhealthy",
+                            url,
+                            name
+                        );
+                        target.healthy = false;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(conf.interval_secs.max(1))).await;
+    }
+}
+
+/// 上游各地址的状态，未使用过地址池时为 None
+pub fn status(name: &str) -> Option<Value> {
+    let pools = pools().lock().unwrap();
+    let now = Instant::now();
+    let targets: Vec<Value> = pools
+        .get(name)?
+        .targets
+        .iter()
+        .map(|t| {
+            json!({
+                "url": t.url,
+                "available": t.available(now),
+                "healthy": t.healthy,
+                "ejected_secs": t
+                    .ejected_until
+                    .map_or(0, |until| until.saturating_duration_since(now).as_secs()),
+                "in_flight": t.in_flight.load(Ordering::SeqCst),
+                "ejections_total": t.ejections_total,
+            })
+        })
+        .collect();
+    Some(Value::from(targets))
+}
+
+/// 输出各地址的可用状态、进行中的请求和摘除次数
+pub fn write_metrics(metrics: &mut Metrics) {
+    let pools = pools().lock().unwrap();
+    let mut names: Vec<&String> = pools.keys().collect();
+    names.sort();
+    let now = Instant::now();
+    let samples = |value: &dyn Fn(&Target) -> f64| -> Vec<Sample> {
+        names
+            .iter()
+            .flat_map(|name| {
+                pools[*name].targets.iter().map(|t| {
+                    (
+                        vec![("upstream", name.as_str()), ("target", t.url.as_str())],
+                        value(t),
+                    )
+                })
+            })
+            .collect()
+    };
+    metrics.gauge(
+        "sso_adapter_upstream_target_available",
+        "Whether the target is healthy and not ejected.",
+        &samples(&|t| if t.available(now) { 1.0 } else { 0.0 }),
+    );
+    metrics.gauge(
+        "sso_adapter_upstream_target_in_flight",
+        "Requests currently sent to the target.",
+        &samples(&|t| t.in_flight.load(Ordering::SeqCst) as f64),
+    );
+    metrics.counter(
+        "sso_adapter_upstream_target_ejections_total",
+        "Times the target was ejected after consecutive failures.",
+        &samples(&|t| t.ejections_total as f64),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::get, Router};
+
+    fn upstream(yaml: &str) -> UpstreamConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn failed() -> Result<reqwest::Response, reqwest::Error> {
+        Ok(http::Response::builder()
+            .status(503)
+            .body("")
+            .unwrap()
+            .into())
+    }
+
+    #[tokio::test]
+    async fn balance_and_passive_ejection() {
+        let conf = upstream(
+            "{targets: [http://a, http://b, http://c], ejection: {consecutive_failures: 2}}",
+        );
+        let urls: Vec<String> = (0..4)
+            .map(|_| pick("pool-rr", &conf, None).unwrap().url.clone())
+            .collect();
+        assert_eq!(urls, ["http://a", "http://b", "http://c", "http://a"]);
+
+        // b 连续失败两次后摘除
+        for _ in 0..2 {
+            let selected = pick("pool-rr", &conf, None).unwrap();
+            assert_eq!(selected.url, "http://b");
+            selected.record(&conf.ejection, &failed());
+            pick("pool-rr", &conf, None).unwrap();
+            pick("pool-rr", &conf, None).unwrap();
+        }
+        let urls: Vec<String> = (0..4)
+            .map(|_| pick("pool-rr", &conf, None).unwrap().url.clone())
+            .collect();
+        assert!(!urls.contains(&"http://b".to_string()));
+
+        // 最少连接
+        let conf =
+            upstream("{targets: [http://a, http://b], balance: {strategy: least_connections}}");
+        let first = pick("pool-lc", &conf, None).unwrap();
+        let second = pick("pool-lc", &conf, None).unwrap();
+        assert_ne!(first.url, second.url);
+        drop(first);
+        let third = pick("pool-lc", &conf, None).unwrap();
+        assert_ne!(third.url, second.url);
+
+        // 一致性哈希：同一个 key 总是选中同一个地址
+        let conf = upstream(
+            "{targets: [http://a, http://b, http://c], balance: {strategy: consistent_hash, cookie: uid}}",
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "lang=zh; uid=42".parse().unwrap());
+        let key = hash_key(&conf.balance, &headers, [127, 0, 0, 1].into()).unwrap();
+        assert_eq!(key, "42");
+        let url = pick("pool-ch", &conf, Some(&key)).unwrap().url.clone();
+        for _ in 0..5 {
+            assert_eq!(pick("pool-ch", &conf, Some(&key)).unwrap().url, url);
+        }
+    }
+
+    #[tokio::test]
+    async fn active_health_check() {
+        let app = Router::new().route("/health", get(|| async { StatusCode::OK }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let app = Router::new().route("/health", get(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let conf = upstream(&format!(
+            "{{targets: ['{}', '{}'], health_check: {{unhealthy_threshold: 1}}}}",
+            up, down
+        ));
+        pick("pool-hc", &conf, None).unwrap();
+        for _ in 0..50 {
+            if status("pool-hc").unwrap()[1]["healthy"] == false {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for _ in 0..4 {
+            assert_eq!(pick("pool-hc", &conf, None).unwrap().url, up);
+        }
+        assert!(crate::metrics::render().contains(&format!(
+            "sso_adapter_upstream_target_available{{upstream=\"pool-hc\",target=\"{}\"}} 0",
+            down
+        )));
+    }
+}
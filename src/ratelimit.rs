@@ -0,0 +1,142 @@
+// 内存令牌桶限流，桶参数随配置重新加载生效
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::RateLimitConfig;
+use crate::mapping::{self, MappingParts};
+
+// 桶数量超过该值时清理长时间未使用的桶
+const PRUNE_THRESHOLD: usize = 10_000;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+fn buckets() -> &'static Mutex<HashMap<String, Bucket>> {
+    static BUCKETS: OnceLock<Mutex<HashMap<String, Bucket>>> = OnceLock::new();
+    BUCKETS.get_or_init(Default::default)
+}
+
+impl RateLimitConfig {
+    fn capacity(&self) -> f64 {
+        self.burst
+            .map(f64::from)
+            .unwrap_or_else(|| self.rate.ceil())
+            .max(1.0)
+    }
+}
+
+/// 限流 key：配置的来源，读取不到时为客户端 IP
+pub fn limit_key(conf: &RateLimitConfig, parts: &MappingParts, client_ip: IpAddr) -> String {
+    conf.key
+        .as_ref()
+        .and_then(|source| mapping::read_source(parts, source))
+        .map(|value| mapping::value_to_string(&value))
+        .filter(|key| !key.is_empty())
+        .unwrap_or_else(|| client_ip.to_string())
+}
+
+/// 从路由和 key 对应的桶中取一个令牌，不足时返回需要等待的时间
+pub fn acquire(route: &str, key: &str, conf: &RateLimitConfig) -> Result<(), Duration> {
+    let now = Instant::now();
+    let capacity = conf.capacity();
+    let mut buckets = buckets().lock().unwrap();
+    if buckets.len() >= PRUNE_THRESHOLD {
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated) < IDLE_TIMEOUT);
+    }
+    let bucket = buckets
+        .entry(format!("{}|{}", route, key))
+        .or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+    // 重新加载后容量可能变小
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * conf.rate).min(capacity);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / conf.rate).unwrap_or(IDLE_TIMEOUT))
+    }
+}
+
+/// 429 响应，Retry-After 向上取整到秒
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [
+            (header::RETRY_AFTER, secs.to_string()),
+            (header::CONTENT_TYPE, "application/json".to_string()),
+        ],
+        json!({ "error": "rate_limited", "retry_after": secs }).to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_refill_and_reload() {
+        let conf: RateLimitConfig = serde_yaml::from_str("{rate: 1, burst: 2}").unwrap();
+        assert!(acquire("/oauth/token", "10.0.0.1", &conf).is_ok());
+        assert!(acquire("/oauth/token", "10.0.0.1", &conf).is_ok());
+        let wait = acquire("/oauth/token", "10.0.0.1", &conf).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        // 不同 key 分别计数
+        assert!(acquire("/oauth/token", "10.0.0.2", &conf).is_ok());
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert!(acquire("/oauth/token", "10.0.0.1", &conf).is_ok());
+        let wait = acquire("/oauth/token", "10.0.0.1", &conf).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        assert_eq!(too_many_requests(wait).headers()[header::RETRY_AFTER], "1");
+
+        // 重新加载为更小的容量
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let reloaded: RateLimitConfig = serde_yaml::from_str("{rate: 0.5, burst: 1}").unwrap();
+        assert!(acquire("/oauth/token", "10.0.0.1", &reloaded).is_ok());
+        assert_eq!(
+            acquire("/oauth/token", "10.0.0.1", &reloaded).unwrap_err(),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn key_from_header_or_client_ip() {
+        let conf: RateLimitConfig =
+            serde_yaml::from_str("{rate: 5, key: !header X-Client-Id}").unwrap();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        let key = |headers: &mut HeaderMap| {
+            limit_key(
+                &conf,
+                &MappingParts {
+                    headers,
+                    query: None,
+                    body: &mut HashMap::new(),
+                    vars: &mut HashMap::new(),
+                },
+                ip,
+            )
+        };
+        assert_eq!(key(&mut headers), "10.0.0.1");
+        headers.insert("x-client-id", "app-1".parse().unwrap());
+        assert_eq!(key(&mut headers), "app-1");
+    }
+}
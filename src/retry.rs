@@ -0,0 +1,129 @@
+// 转发重试：指数退避加随机抖动，只重试幂等方法或显式允许的 POST
+use hyper::Method;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response};
+use std::time::Duration;
+use tracing::{event, Level};
+
+use crate::config::RetryConfig;
+
+impl RetryConfig {
+    /// 该方法的请求是否可以重试
+    pub fn allows(&self, method: &Method) -> bool {
+        self.max_attempts > 1
+            && (method.is_idempotent() || (self.retry_post && method == Method::POST))
+    }
+
+    // 第 attempt 次重试前的等待时间，在退避时间的一半到全部之间随机
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(32))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(base / 2 + rand::rng().random_range(0..=base - base / 2))
+    }
+}
+
+/// 发送请求，build 每次尝试重新构造请求；未配置重试时只发送一次
+pub async fn send(
+    conf: Option<&RetryConfig>,
+    mut build: impl FnMut() -> RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    let max_attempts = conf.map_or(1, |c| c.max_attempts.max(1));
+    let mut attempt = 1;
+    loop {
+        let result = build().send().await;
+        let Some(conf) = conf.filter(|_| attempt < max_attempts) else {
+            return result;
+        };
+        match &result {
+            Ok(response) if conf.on_status.contains(&response.status().as_u16()) => {
+                event!(
+                    Level::WARN,
+                    "Upstream returned {}, retry attempt {}/{}",
+                    response.status(),
+                    attempt + 1,
+                    max_attempts
+                );
+            }
+            Err(e) if conf.on_connect_error && e.is_connect() => {
+                event!(
+                    Level::WARN,
+                    "Upstream connect failed: {}, retry attempt {}/{}",
+                    e,
+                    attempt + 1,
+                    max_attempts
+                );
+            }
+            _ => return result,
+        }
+        drop(result);
+        tokio::time::sleep(conf.backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::StatusCode, routing::any, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn backoff_grows_with_jitter_and_cap() {
+        let conf: RetryConfig =
+            serde_yaml::from_str("{initial_backoff_ms: 100, max_backoff_ms: 300}").unwrap();
+        for _ in 0..20 {
+            let first = conf.backoff(1).as_millis();
+            assert!((50..=100).contains(&first));
+            let second = conf.backoff(2).as_millis();
+            assert!((100..=200).contains(&second));
+            let capped = conf.backoff(10).as_millis();
+            assert!((150..=300).contains(&capped));
+        }
+        assert!(conf.allows(&Method::GET));
+        assert!(!conf.allows(&Method::POST));
+    }
+
+    #[tokio::test]
+    async fn retry_status_and_replay_body() {
+        // 前两次返回 503，之后返回收到的 body
+        async fn flaky(
+            State(calls): State<Arc<AtomicUsize>>,
+            body: String,
+        ) -> (StatusCode, String) {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                (StatusCode::SERVICE_UNAVAILABLE, String::new())
+            } else {
+                (StatusCode::OK, body)
+            }
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/", any(flaky))
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let conf: RetryConfig =
+            serde_yaml::from_str("{initial_backoff_ms: 1, retry_post: true}").unwrap();
+        let body = axum::body::Bytes::from_static(b"payload");
+        let response = send(Some(&conf), || client.post(&url).body(body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "payload");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // 次数用完时返回最后一次的响应
+        calls.store(0, Ordering::SeqCst);
+        let conf: RetryConfig =
+            serde_yaml::from_str("{max_attempts: 2, initial_backoff_ms: 1}").unwrap();
+        let response = send(Some(&conf), || client.get(&url)).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,288 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use hmac::{Hmac, Mac};
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tracing::{event, Level};
+
+use crate::cache::FileCache;
+use crate::config::ScriptConfig;
+use crate::mapping::{apply_parts_json, parts_to_json, MappingParts};
+
+thread_local! {
+    // 当前线程上脚本开始执行的时间，脚本同步执行，用于超时判断
+    static STARTED: Cell<Instant> = Cell::new(Instant::now());
+}
+
+// (max_operations, timeout_ms) -> 引擎
+type Engines = Mutex<HashMap<(u64, u64), Arc<Engine>>>;
+
+// 引擎按执行限制缓存：max_operations 限制操作数，timeout_ms 限制执行时间
+fn engine(conf: &ScriptConfig) -> Arc<Engine> {
+    static ENGINES: OnceLock<Engines> = OnceLock::new();
+    ENGINES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry((conf.max_operations, conf.timeout_ms))
+        .or_insert_with(|| Arc::new(build_engine(conf)))
+        .clone()
+}
+
+fn build_engine(conf: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(conf.max_operations);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_call_levels(32);
+
+    let timeout = Duration::from_millis(conf.timeout_ms);
+    engine.on_progress(move |_| {
+        if STARTED.get().elapsed() > timeout {
+            Some(Dynamic::from("script timeout"))
+        } else {
+            None
+        }
+    });
+
+    engine
+        .register_fn("sha256_hex", |s: &str| hex::encode(Sha256::digest(s)))
+        .register_fn("hmac_sha256_hex", |key: &str, s: &str| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+            mac.update(s.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        })
+        .register_fn("base64_encode", |s: &str| BASE64_STANDARD.encode(s))
+        .register_fn("base64_decode", |s: &str| {
+            BASE64_STANDARD
+                .decode(s)
+                .ok()
+                .and_then(|b| String::from_utf8(b).ok())
+                .unwrap_or_default()
+        })
+        .register_fn("url_encode", |s: &str| urlencoding::encode(s).into_owned())
+        .register_fn("timestamp", || {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default()
+        });
+    engine
+}
+
+// 脚本文件按路径和修改时间缓存编译结果，文件修改后替换旧的编译结果
+static SCRIPT_FILES: FileCache<AST> = FileCache::new();
+
+// 内联脚本按源码缓存的上限，超过时清空，避免配置反复修改后旧脚本一直留在内存中
+const MAX_INLINE_SCRIPTS: usize = 256;
+
+// 各引擎的解析限制相同，编译结果可在引擎间共用
+fn compile(engine: &Engine, conf: &ScriptConfig) -> anyhow::Result<Arc<AST>> {
+    static INLINE: OnceLock<Mutex<HashMap<String, Arc<AST>>>> = OnceLock::new();
+    match (&conf.source, &conf.file) {
+        (Some(source), _) => {
+            let inline = INLINE.get_or_init(Default::default);
+            if let Some(ast) = inline.lock().unwrap().get(source) {
+                return Ok(ast.clone());
+            }
+            let ast = Arc::new(engine.compile(source)?);
+            let mut inline = inline.lock().unwrap();
+            if inline.len() >= MAX_INLINE_SCRIPTS {
+                inline.clear();
+            }
+            inline.insert(source.clone(), ast.clone());
+            Ok(ast)
+        }
+        (None, Some(file)) => SCRIPT_FILES.get(file, |p: &Path| {
+            Ok(engine.compile(std::fs::read_to_string(p)?)?)
+        }),
+        (None, None) => anyhow::bail!("script requires source or file"),
+    }
+}
+
+fn script_name(conf: &ScriptConfig) -> &str {
+    conf.file.as_deref().unwrap_or("<inline>")
+}
+
+fn to_dynamic(value: &Value) -> anyhow::Result<Dynamic> {
+    rhai::serde::to_dynamic(value).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+fn from_dynamic(value: &Dynamic) -> anyhow::Result<Value> {
+    Ok(serde_json::to_value(value)?)
+}
+
+fn take_map(scope: &mut Scope, name: &str) -> anyhow::Result<Map<String, Value>> {
+    let value = scope
+        .get(name)
+        .map(from_dynamic)
+        .transpose()?
+        .unwrap_or(Value::Null);
+    match value {
+        Value::Object(map) => Ok(map),
+        Value::Null => Ok(Map::new()),
+        _ => anyhow::bail!("script variable {} must be a map", name),
+    }
+}
+
+/// 执行阶段脚本，脚本可读写 headers、query、body（扁平 key）和 vars
+pub fn run_stage_script(conf: &ScriptConfig, parts: &mut MappingParts) -> anyhow::Result<()> {
+    let engine = engine(conf);
+    let ast = compile(&engine, conf)?;
+
+    let Value::Object(mut input) = parts_to_json(parts) else {
+        unreachable!()
+    };
+    let mut scope = Scope::new();
+    for name in ["headers", "query", "body", "vars"] {
+        scope.push(name, to_dynamic(&input.remove(name).unwrap_or_default())?);
+    }
+
+    STARTED.set(Instant::now());
+    engine
+        .run_ast_with_scope(&mut scope, &ast)
+        .map_err(|e| anyhow::anyhow!("script error: {}", e))?;
+
+    // 回写
+    let mut output = Map::new();
+    for name in ["headers", "query", "body", "vars"] {
+        output.insert(name.to_string(), Value::Object(take_map(&mut scope, name)?));
+    }
+    apply_parts_json(parts, Value::Object(output))?;
+
+    Ok(())
+}
+
+/// 脚本转换：输入 value（及目标当前值 dst），脚本返回值为转换结果，返回 () 视为空；
+/// 执行失败时记录错误并返回空，映射按转换结果为空处理
+pub fn run_value_script(
+    conf: &ScriptConfig,
+    value: &Value,
+    dst_value: Option<&str>,
+) -> Option<Value> {
+    let run = || -> anyhow::Result<Value> {
+        let engine = engine(conf);
+        let ast = compile(&engine, conf)?;
+        let mut scope = Scope::new();
+        scope.push("value", to_dynamic(value)?);
+        scope.push(
+            "dst",
+            dst_value
+                .map(|s| Dynamic::from(s.to_string()))
+                .unwrap_or(Dynamic::UNIT),
+        );
+        STARTED.set(Instant::now());
+        let result: Dynamic = engine
+            .eval_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| anyhow::anyhow!("script error: {}", e))?;
+        from_dynamic(&result)
+    };
+    match run() {
+        Ok(Value::Null) => None,
+        Ok(v) => Some(v),
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Script transformation {} failed on {}: {}",
+                script_name(conf),
+                value,
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+
+    fn script(source: &str) -> ScriptConfig {
+        ScriptConfig {
+            source: Some(source.to_string()),
+            file: None,
+            max_operations: 10_000,
+            timeout_ms: 100,
+        }
+    }
+
+    #[test]
+    fn stage_script_signs_sorted_query() {
+        let mut headers = HeaderMap::new();
+        let mut query = HashMap::from([
+            ("b".to_string(), vec!["2".to_string()]),
+            ("a".to_string(), vec!["1".to_string()]),
+        ]);
+        let mut body = HashMap::new();
+        let mut vars = HashMap::new();
+        let conf = script(
+            r#"
+            let keys = query.keys();
+            keys.sort();
+            let canonical = "";
+            for k in keys {
+                if canonical != "" { canonical += "&"; }
+                canonical += k + "=" + query[k];
+            }
+            query.sign = hmac_sha256_hex("secret", canonical);
+            headers["x-canonical"] = canonical;
+            vars.signed = true;
+            "#,
+        );
+        run_stage_script(
+            &conf,
+            &mut MappingParts {
+                headers: &mut headers,
+                query: Some(&mut query),
+                body: &mut body,
+                vars: &mut vars,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(headers.get("x-canonical").unwrap(), "a=1&b=2");
+        assert_eq!(query.get("sign").unwrap()[0].len(), 64);
+        assert_eq!(vars.get("signed"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn runaway_script_is_stopped() {
+        let conf = script("loop { }");
+        assert_eq!(run_value_script(&conf, &Value::from("x"), None), None);
+    }
+
+    #[test]
+    fn value_script_compiled_once() {
+        let conf = script("value + dst");
+        let engine = engine(&conf);
+        let ast = compile(&engine, &conf).unwrap();
+        assert!(Arc::ptr_eq(&ast, &compile(&engine, &conf).unwrap()));
+        assert_eq!(
+            run_value_script(&conf, &Value::from("a"), Some("b")),
+            Some(Value::from("ab"))
+        );
+
+        // 文件脚本按路径缓存编译结果
+        let path = std::env::temp_dir().join(format!("script-{}.rhai", std::process::id()));
+        std::fs::write(&path, "value + 1").unwrap();
+        let conf = ScriptConfig {
+            source: None,
+            file: Some(path.to_string_lossy().into_owned()),
+            ..script("")
+        };
+        let ast = compile(&engine, &conf).unwrap();
+        assert!(Arc::ptr_eq(&ast, &compile(&engine, &conf).unwrap()));
+        assert_eq!(
+            run_value_script(&conf, &Value::from(1), None),
+            Some(Value::from(2))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
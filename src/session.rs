@@ -0,0 +1,300 @@
+// 服务端会话：cookie 只保存签名的会话 id，数据保存在内存或文件中
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use hyper::{header, header::HeaderValue, HeaderMap};
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{SessionConfig, SessionStoreType};
+use crate::{flat_map_to_json, json_to_flat_map};
+
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 内存存储：会话 id -> (过期时间, 数据)
+fn memory() -> &'static Mutex<HashMap<String, (u64, Value)>> {
+    static MEMORY: OnceLock<Mutex<HashMap<String, (u64, Value)>>> = OnceLock::new();
+    MEMORY.get_or_init(Default::default)
+}
+
+fn file_path(conf: &SessionConfig, id: &str) -> PathBuf {
+    PathBuf::from(&conf.dir).join(format!("{}.json", id))
+}
+
+fn store_load(conf: &SessionConfig, id: &str) -> Option<Value> {
+    match conf.store {
+        SessionStoreType::Memory => {
+            let mut sessions = memory().lock().unwrap();
+            match sessions.get(id) {
+                Some((expires_at, data)) if *expires_at > now() => Some(data.clone()),
+                Some(_) => {
+                    sessions.remove(id);
+                    None
+                }
+                None => None,
+            }
+        }
+        SessionStoreType::File => {
+            let path = file_path(conf, id);
+            let stored: Value = serde_json::from_str(&std::fs::read_to_string(&path).ok()?).ok()?;
+            if stored["expires_at"].as_u64()? > now() {
+                Some(stored["data"].clone())
+            } else {
+                let _ = std::fs::remove_file(&path);
+                None
+            }
+        }
+    }
+}
+
+fn store_save(conf: &SessionConfig, id: &str, data: &Value) -> anyhow::Result<()> {
+    let now = now();
+    let expires_at = now + conf.ttl_secs;
+    match conf.store {
+        SessionStoreType::Memory => {
+            let mut sessions = memory().lock().unwrap();
+            sessions.retain(|_, (expires_at, _)| *expires_at > now);
+            sessions.insert(id.to_string(), (expires_at, data.clone()));
+        }
+        SessionStoreType::File => {
+            std::fs::create_dir_all(&conf.dir)?;
+            // 先写临时文件再改名，避免读到不完整的数据
+            let path = file_path(conf, id);
+            let tmp = path.with_extension("tmp");
+            std::fs::write(
+                &tmp,
+                json!({"expires_at": expires_at, "data": data}).to_string(),
+            )?;
+            std::fs::rename(&tmp, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn store_delete(conf: &SessionConfig, id: &str) {
+    match conf.store {
+        SessionStoreType::Memory => {
+            memory().lock().unwrap().remove(id);
+        }
+        SessionStoreType::File => {
+            let _ = std::fs::remove_file(file_path(conf, id));
+        }
+    }
+}
+
+fn mac(conf: &SessionConfig, id: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(conf.secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(id.as_bytes());
+    mac
+}
+
+// cookie 值为 {id}.{hmac}
+fn signed_id(conf: &SessionConfig, id: &str) -> String {
+    let signature = mac(conf, id).finalize().into_bytes();
+    format!("{}.{}", id, BASE64_URL_SAFE_NO_PAD.encode(signature))
+}
+
+fn verify_id(conf: &SessionConfig, value: &str) -> Option<String> {
+    let (id, signature) = value.split_once('.')?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+    mac(conf, id).verify_slice(&signature).ok()?;
+    Some(id.to_string())
+}
+
+fn new_id() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn set_cookie(conf: &SessionConfig, value: &str, max_age: u64) -> Option<HeaderValue> {
+    let secure = if conf.secure { "; Secure" } else { "" };
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite={}{}",
+        conf.cookie_name, value, max_age, conf.same_site, secure
+    ))
+    .ok()
+}
+
+// 取出会话 cookie 并从 Cookie header 中移除，不转发给上游
+fn take_cookie(name: &str, headers: &mut HeaderMap) -> Option<String> {
+    let mut found = None;
+    let mut rest = Vec::new();
+    for value in headers.get_all(header::COOKIE) {
+        for pair in value.to_str().unwrap_or_default().split(';') {
+            let pair = pair.trim();
+            match pair.split_once('=') {
+                Some((key, value)) if key == name => found = Some(value.to_string()),
+                _ if !pair.is_empty() => rest.push(pair.to_string()),
+                _ => {}
+            }
+        }
+    }
+    if found.is_some() {
+        headers.remove(header::COOKIE);
+        if let Ok(value) = HeaderValue::from_str(&rest.join("; ")) {
+            if !rest.is_empty() {
+                headers.insert(header::COOKIE, value);
+            }
+        }
+    }
+    found
+}
+
+/// 当前请求的会话
+#[derive(Debug, Default)]
+pub struct Session {
+    id: Option<String>,
+    // 已保存的数据，用于判断是否有变化
+    data: HashMap<String, Value>,
+    // 需要写回客户端的 cookie
+    pub set_cookie: Option<HeaderValue>,
+}
+
+/// 读取会话 cookie，数据按 `{var}.{key}` 写入变量
+pub fn load(
+    conf: &SessionConfig,
+    headers: &mut HeaderMap,
+    vars: &mut HashMap<String, Value>,
+) -> Session {
+    let id = take_cookie(&conf.cookie_name, headers).and_then(|v| verify_id(conf, &v));
+    let Some((id, data)) = id.and_then(|id| store_load(conf, &id).map(|data| (id, data))) else {
+        return Session::default();
+    };
+    json_to_flat_map(&data, &conf.var, vars);
+    let mut flat = HashMap::new();
+    json_to_flat_map(&data, "", &mut flat);
+    Session {
+        id: Some(id),
+        data: flat,
+        set_cookie: None,
+    }
+}
+
+/// 变量中的会话数据有变化时保存，新建或清空会话时设置 cookie
+pub fn save(
+    conf: &SessionConfig,
+    session: &mut Session,
+    vars: &HashMap<String, Value>,
+) -> anyhow::Result<()> {
+    let prefix = format!("{}.", conf.var);
+    let current: HashMap<String, Value> = vars
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|k| (k.to_string(), v.clone())))
+        .collect();
+    let data = flat_map_to_json(&current);
+    let mut flat = HashMap::new();
+    json_to_flat_map(&data, "", &mut flat);
+    if flat == session.data {
+        return Ok(());
+    }
+
+    match (session.id.clone(), flat.is_empty()) {
+        (Some(id), true) => {
+            store_delete(conf, &id);
+            session.id = None;
+            session.set_cookie = set_cookie(conf, "", 0);
+        }
+        (None, true) => {}
+        (Some(id), false) => store_save(conf, &id, &data)?,
+        (None, false) => {
+            let id = new_id();
+            store_save(conf, &id, &data)?;
+            session.set_cookie = set_cookie(conf, &signed_id(conf, &id), conf.ttl_secs);
+            session.id = Some(id);
+        }
+    }
+    session.data = flat;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(conf: &SessionConfig) {
+        // authorize 阶段写入 state 和 PKCE verifier
+        let mut vars = HashMap::new();
+        let mut session = load(conf, &mut HeaderMap::new(), &mut vars);
+        vars.insert("session.state".to_string(), Value::from("xyz"));
+        vars.insert("session.code_verifier".to_string(), Value::from("v-123"));
+        save(conf, &mut session, &vars).unwrap();
+        let cookie = session.set_cookie.unwrap();
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.contains("HttpOnly; SameSite=Lax; Secure"));
+        let value = cookie.split(';').next().unwrap();
+
+        // callback 阶段读取，会话 cookie 不转发给上游
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("lang=zh; {}", value).parse().unwrap(),
+        );
+        let mut vars = HashMap::new();
+        let mut session = load(conf, &mut headers, &mut vars);
+        assert_eq!(vars["session.state"], "xyz");
+        assert_eq!(vars["session.code_verifier"], "v-123");
+        assert_eq!(headers[header::COOKIE], "lang=zh");
+
+        // 取出后清空会话
+        vars.retain(|k, _| !k.starts_with("session."));
+        save(conf, &mut session, &vars).unwrap();
+        assert!(session
+            .set_cookie
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains("Max-Age=0"));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, value.parse().unwrap());
+        let mut vars = HashMap::new();
+        load(conf, &mut headers, &mut vars);
+        assert!(vars.is_empty());
+    }
+
+    #[test]
+    fn memory_and_file_store_round_trip() {
+        let mut conf: SessionConfig = serde_yaml::from_str("secret: s3cr3t").unwrap();
+        round_trip(&conf);
+
+        let dir = std::env::temp_dir().join(format!("sessions-{}", std::process::id()));
+        conf.store = SessionStoreType::File;
+        conf.dir = dir.to_string_lossy().into_owned();
+        round_trip(&conf);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_tampered_cookie() {
+        let conf: SessionConfig = serde_yaml::from_str("secret: s3cr3t").unwrap();
+        let mut vars = HashMap::new();
+        let mut session = Session::default();
+        vars.insert("session.state".to_string(), Value::from("xyz"));
+        save(&conf, &mut session, &vars).unwrap();
+        let id = session.id.unwrap();
+
+        let other: SessionConfig = serde_yaml::from_str("secret: other").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            format!("sso_adapter_session={}", signed_id(&other, &id))
+                .parse()
+                .unwrap(),
+        );
+        let mut vars = HashMap::new();
+        load(&conf, &mut headers, &mut vars);
+        assert!(vars.is_empty());
+        assert!(headers.get(header::COOKIE).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+// 上游请求签名，在映射完成后按最终的 method、url、header 和 body 计算
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use hmac::{Hmac, Mac};
+use hyper::{
+    header,
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Method,
+};
+use rand::RngCore;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256, Sha512};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::config::{
+    AwsSigV4Config, HmacAlgorithm, HmacSigningConfig, SignatureEncoding, SigningConfig,
+};
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+impl SigningConfig {
+    /// 签名是否需要读取完整 body
+    pub fn needs_body(&self) -> bool {
+        match self {
+            SigningConfig::Hmac(_) => true,
+            SigningConfig::AwsSigV4(conf) => !conf.unsigned_payload,
+        }
+    }
+}
+
+fn hmac(algorithm: HmacAlgorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    match algorithm {
+        HmacAlgorithm::Sha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+        HmacAlgorithm::Sha512 => {
+            let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("hmac accepts any key length");
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+}
+
+// RFC 3986 编码，只保留 unreserved 字符
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// 按 key、value 排序并编码的 query
+fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k), uri_encode(&v)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// 请求实际发送的 host
+fn host(url: &Url, headers: &HeaderMap) -> String {
+    if let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) {
+        return host.to_string();
+    }
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &str, value: &str) -> anyhow::Result<()> {
+    headers.insert(HeaderName::try_from(name)?, HeaderValue::from_str(value)?);
+    Ok(())
+}
+
+/// 对上游请求签名，签名结果写入 header；body 为 None 表示流式转发
+pub fn sign(
+    conf: &SigningConfig,
+    method: &Method,
+    url: &str,
+    headers: &mut HeaderMap,
+    body: Option<&[u8]>,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let url = Url::parse(url)?;
+    match conf {
+        SigningConfig::Hmac(conf) => sign_hmac(conf, method, &url, headers, body, now),
+        SigningConfig::AwsSigV4(conf) => sign_aws_sigv4(conf, method, &url, headers, body, now),
+    }
+}
+
+fn sign_hmac(
+    conf: &HmacSigningConfig,
+    method: &Method,
+    url: &Url,
+    headers: &mut HeaderMap,
+    body: Option<&[u8]>,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let timestamp = now.duration_since(UNIX_EPOCH)?.as_secs().to_string();
+    let nonce = {
+        let mut bytes = [0u8; 16];
+        rand::rng().fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    };
+    let key_id = conf.key_id.clone().unwrap_or_default();
+    let body_sha256 = body
+        .map(|b| hex::encode(Sha256::digest(b)))
+        .unwrap_or_else(|| UNSIGNED_PAYLOAD.to_string());
+
+    let placeholder = Regex::new(r"\{([a-z0-9_]+)(?:\.([A-Za-z0-9_-]+))?\}").unwrap();
+    let render = |template: &str, signature: &str| {
+        placeholder
+            .replace_all(template, |caps: &Captures| match (&caps[1], caps.get(2)) {
+                ("method", None) => method.to_string(),
+                ("path", None) => url.path().to_string(),
+                ("query", None) => canonical_query(url),
+                ("body_sha256", None) => body_sha256.clone(),
+                ("timestamp", None) => timestamp.clone(),
+                ("nonce", None) => nonce.clone(),
+                ("key_id", None) => key_id.clone(),
+                ("host", None) => host(url, headers),
+                ("signature", None) => signature.to_string(),
+                ("header", Some(name)) => headers
+                    .get(name.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                _ => caps[0].to_string(),
+            })
+            .into_owned()
+    };
+
+    let canonical = conf
+        .canonical
+        .iter()
+        .map(|line| render(line, ""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let digest = hmac(conf.algorithm, conf.secret.as_bytes(), canonical.as_bytes());
+    let signature = match conf.encoding {
+        SignatureEncoding::Hex => hex::encode(digest),
+        SignatureEncoding::Base64 => BASE64_STANDARD.encode(digest),
+    };
+    let value = render(&conf.signature_format, &signature);
+
+    if let Some(name) = &conf.timestamp_header {
+        insert_header(headers, name, &timestamp)?;
+    }
+    if let Some(name) = &conf.nonce_header {
+        insert_header(headers, name, &nonce)?;
+    }
+    if let (Some(name), Some(key_id)) = (&conf.key_id_header, &conf.key_id) {
+        insert_header(headers, name, key_id)?;
+    }
+    insert_header(headers, &conf.signature_header, &value)
+}
+
+// unix 时间转为 (20150830T123600Z, 20150830)
+fn amz_date(now: SystemTime) -> anyhow::Result<(String, String)> {
+    let secs = now.duration_since(UNIX_EPOCH)?.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil_from_days, 公历日期
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    Ok((
+        format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        ),
+        date,
+    ))
+}
+
+fn sign_aws_sigv4(
+    conf: &AwsSigV4Config,
+    method: &Method,
+    url: &Url,
+    headers: &mut HeaderMap,
+    body: Option<&[u8]>,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let (amz_date, date) = amz_date(now)?;
+    let payload_hash = match body {
+        Some(body) if !conf.unsigned_payload => hex::encode(Sha256::digest(body)),
+        _ => UNSIGNED_PAYLOAD.to_string(),
+    };
+
+    let host = host(url, headers);
+    insert_header(headers, "host", &host)?;
+    insert_header(headers, "x-amz-date", &amz_date)?;
+    if conf.content_sha256_header || payload_hash == UNSIGNED_PAYLOAD {
+        insert_header(headers, "x-amz-content-sha256", &payload_hash)?;
+    }
+    if let Some(token) = &conf.session_token {
+        insert_header(headers, "x-amz-security-token", token)?;
+    }
+
+    // 签名 host、content-type 和所有 x-amz-* header
+    let mut names: Vec<&str> = headers
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| *name == "host" || *name == "content-type" || name.starts_with("x-amz-"))
+        .collect();
+    names.sort();
+    names.dedup();
+    let canonical_headers: String = names
+        .iter()
+        .map(|name| {
+            let values: Vec<String> = headers
+                .get_all(*name)
+                .iter()
+                .map(|v| {
+                    String::from_utf8_lossy(v.as_bytes())
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            format!("{}:{}\n", name, values.join(","))
+        })
+        .collect();
+    let signed_headers = names.join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        url.path(),
+        canonical_query(url),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, conf.region, conf.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = format!("AWS4{}", conf.secret_access_key);
+    let key = [
+        date.as_str(),
+        conf.region.as_str(),
+        conf.service.as_str(),
+        "aws4_request",
+    ]
+    .iter()
+    .fold(key.into_bytes(), |key, part| {
+        hmac(HmacAlgorithm::Sha256, &key, part.as_bytes())
+    });
+    let signature = hex::encode(hmac(HmacAlgorithm::Sha256, &key, string_to_sign.as_bytes()));
+
+    insert_header(
+        headers,
+        "authorization",
+        &format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            conf.access_key_id, scope, signed_headers, signature
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2015-08-30T12:36:00Z
+    fn test_time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1440938160)
+    }
+
+    #[test]
+    fn aws_sigv4_test_suite() {
+        let conf: SigningConfig = serde_yaml::from_str(
+            r#"
+scheme: aws_sigv4
+access_key_id: AKIDEXAMPLE
+secret_access_key: wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY
+region: us-east-1
+service: service
+"#,
+        )
+        .unwrap();
+        let sign_url = |url: &str| {
+            let mut headers = HeaderMap::new();
+            sign(
+                &conf,
+                &Method::GET,
+                url,
+                &mut headers,
+                Some(b""),
+                test_time(),
+            )
+            .unwrap();
+            headers
+        };
+
+        // get-vanilla
+        let headers = sign_url("https://example.amazonaws.com/");
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+        assert_eq!(
+            headers[header::AUTHORIZATION],
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+
+        // get-vanilla-query-order-key-case
+        let headers = sign_url("https://example.amazonaws.com/?Param2=value2&Param1=value1");
+        assert!(headers[header::AUTHORIZATION].to_str().unwrap().ends_with(
+            "Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        ));
+    }
+
+    #[test]
+    fn generic_hmac_over_final_request() {
+        let conf: SigningConfig = serde_yaml::from_str(
+            r#"
+scheme: hmac
+secret: s3cr3t
+key_id: partner-1
+key_id_header: X-Key-Id
+canonical: ["{method}", "{path}", "{query}", "{timestamp}", "{header.content-type}", "{body_sha256}"]
+signature_format: "HMAC-SHA256 {key_id}:{signature}"
+"#,
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        sign(
+            &conf,
+            &Method::POST,
+            "https://partner.example.com/v1/orders?b=2&a=x%20y",
+            &mut headers,
+            Some(br#"{"id":1}"#),
+            test_time(),
+        )
+        .unwrap();
+
+        let canonical = format!(
+            "POST\n/v1/orders\na=x%20y&b=2\n1440938160\napplication/json\n{}",
+            hex::encode(Sha256::digest(br#"{"id":1}"#))
+        );
+        let expected = hex::encode(hmac(HmacAlgorithm::Sha256, b"s3cr3t", canonical.as_bytes()));
+        assert_eq!(headers["x-timestamp"], "1440938160");
+        assert_eq!(headers["x-key-id"], "partner-1");
+        assert_eq!(
+            headers["x-signature"],
+            format!("HMAC-SHA256 partner-1:{}", expected).as_str()
+        );
+    }
+}
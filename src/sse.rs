@@ -0,0 +1,522 @@
+use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
+use hyper::{header, header::HeaderValue, HeaderMap, StatusCode};
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+use tracing::{event, Level};
+
+use crate::config::{
+    MixMapping, MixSource, RequestMapConfig, ResponseMapConfig, ServiceType, SseConfig,
+};
+use crate::mapping::{self, MappingParts};
+use crate::{flat_map_to_json, json_to_flat_map};
+
+/// SSE 事件
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+}
+
+impl SseEvent {
+    // 序列化为 event:/data:/id: 帧，多行 data 拆为多个 data 行，没有 data 时不写 data 行
+    pub fn to_frame(&self) -> String {
+        let mut frame = String::new();
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry));
+        }
+        if !self.data.is_empty() {
+            for line in self.data.split('\n') {
+                frame.push_str(&format!("data: {}\n", line));
+            }
+        }
+        frame.push('\n');
+        frame
+    }
+}
+
+/// 增量解析 SSE 流，事件可跨 chunk
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buf: Vec<u8>,
+    current: SseEvent,
+    data_lines: Vec<String>,
+    has_fields: bool,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let mut line: Vec<u8> = self.buf.drain(..=pos).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let line = String::from_utf8_lossy(&line);
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    // 流结束时输出未以空行结束的事件
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buf.is_empty() {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.buf)).into_owned();
+            if let Some(event) = self.process_line(line.trim_end_matches('\r')) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        // 注释行
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((f, v)) => (f, v.strip_prefix(' ').unwrap_or(v)),
+            None => (line, ""),
+        };
+        self.has_fields = true;
+        match field {
+            "event" => self.current.event = Some(value.to_string()),
+            "data" => self.data_lines.push(value.to_string()),
+            "id" => self.current.id = Some(value.to_string()),
+            "retry" => self.current.retry = value.parse().ok(),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        if !self.has_fields {
+            return None;
+        }
+        let mut event = std::mem::take(&mut self.current);
+        event.data = std::mem::take(&mut self.data_lines).join("\n");
+        self.has_fields = false;
+        Some(event)
+    }
+}
+
+/// 上游出错时发送的 error 事件
+pub fn error_event(message: &str) -> SseEvent {
+    SseEvent {
+        event: Some("error".to_string()),
+        data: serde_json::json!({ "message": message }).to_string(),
+        ..Default::default()
+    }
+}
+
+// 客户端断开时 body 流被丢弃，上游连接随之关闭
+struct DisconnectGuard {
+    finished: bool,
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            event!(Level::INFO, "SSE client disconnected, upstream request cancelled");
+        }
+    }
+}
+
+// 分块是否停在事件边界上，心跳只能插在两个事件之间
+fn ends_event(chunk: &[u8]) -> bool {
+    chunk.ends_with(b"\n\n") || chunk.ends_with(b"\r\n\r\n") || chunk.ends_with(b"\r\r")
+}
+
+/// 在下游流空闲且停在事件边界时插入注释心跳，下游流结束或客户端断开时结束
+pub fn keepalive<S>(
+    inner: S,
+    conf: &SseConfig,
+) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>>
+where
+    S: Stream<Item = Result<Vec<u8>, std::io::Error>> + Send + 'static,
+{
+    let period = (conf.keepalive_secs > 0).then(|| Duration::from_secs(conf.keepalive_secs));
+    let comment = format!(": {}\n\n", conf.keepalive_comment);
+    async_stream::stream! {
+        let mut guard = DisconnectGuard { finished: false };
+        let mut inner = std::pin::pin!(inner);
+        let mut at_boundary = true;
+        loop {
+            let next = match period {
+                Some(period) => match tokio::time::timeout(period, inner.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        if at_boundary {
+                            yield Ok(comment.clone().into_bytes());
+                        }
+                        continue;
+                    }
+                },
+                None => inner.next().await,
+            };
+            match next {
+                Some(item) => {
+                    if let Ok(chunk) = &item {
+                        if !chunk.is_empty() {
+                            at_boundary = ends_event(chunk);
+                        }
+                    }
+                    yield item
+                }
+                None => break,
+            }
+        }
+        guard.finished = true;
+    }
+}
+
+/// 透传上游字节，上游出错时发送 error 事件并结束；压缩的流无法插入事件，出错时直接结束
+pub fn passthrough_stream<S, E>(
+    upstream: S,
+    compressed: bool,
+) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: std::fmt::Display,
+{
+    async_stream::stream! {
+        let mut upstream = std::pin::pin!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            match chunk {
+                Ok(c) => {
+                    event!(Level::DEBUG, "SSE chunk: {:?}", c);
+                    yield Ok(c.to_vec());
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "SSE upstream error: {}", e);
+                    if !compressed {
+                        // 先结束可能未完成的事件，避免与 error 事件拼接
+                        yield Ok(format!("\n\n{}", error_event(&e.to_string()).to_frame()).into_bytes());
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// 是否需要逐事件处理
+pub fn needs_event_mapping(conf: &ResponseMapConfig) -> bool {
+    conf.mix_mappings.iter().any(MixMapping::involves_body)
+        || conf.script.is_some()
+        || conf.plugin.is_some()
+}
+
+// 旧版 `!sse bodyfield-stream` 配置，格式错误时忽略
+pub fn legacy_source(source: &str) -> Option<MixSource> {
+    let Some((src_type, src_value)) = source.split_once('-') else {
+        event!(Level::WARN, "Invalid SSE source format: {}", source);
+        return None;
+    };
+    match src_type.to_lowercase().as_str() {
+        "bodyfield" => Some(MixSource::BodyField(src_value.to_string())),
+        "header" => Some(MixSource::Header(src_value.to_string())),
+        "query" => Some(MixSource::Query(src_value.to_string())),
+        _ => {
+            event!(Level::WARN, "Invalid SSE source type: {}", src_type);
+            None
+        }
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => matches!(s.to_lowercase().as_str(), "true" | "1" | "yes"),
+        Value::Array(arr) => arr.first().is_some_and(is_truthy),
+        _ => false,
+    }
+}
+
+/// 判断流式请求的源字段：旧版 !sse 配置或 stream 配置
+pub fn stream_source(conf: &RequestMapConfig) -> Option<MixSource> {
+    match &conf.target_service {
+        ServiceType::SSE(source) => legacy_source(source),
+        _ => conf.stream.clone(),
+    }
+}
+
+/// 请求是否期望流式响应：Accept 为 text/event-stream，或 stream 源字段为真
+pub fn request_wants_stream(
+    accept: Option<&HeaderValue>,
+    source: Option<&MixSource>,
+    parts: &MappingParts,
+) -> bool {
+    let accepts_sse = accept
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(mime::TEXT_EVENT_STREAM.essence_str()));
+    accepts_sse
+        || source
+            .and_then(|src| mapping::read_source(parts, src))
+            .is_some_and(|v| is_truthy(&v))
+}
+
+/// 上游响应是否为 SSE
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(mime::TEXT_EVENT_STREAM.essence_str()))
+}
+
+/// 响应是否按 sse 处理：以上游 Content-Type 为准，上游未声明时才参考请求是否期望流式
+pub fn is_sse_response(headers: &HeaderMap, status: StatusCode, wants_stream: bool) -> bool {
+    if headers.contains_key(header::CONTENT_TYPE) {
+        is_event_stream(headers)
+    } else {
+        wants_stream && status.is_success()
+    }
+}
+
+/// 对单个事件的 json 对象或数组 data 执行 response 阶段，其余 data 原样返回
+pub fn map_event(
+    mut event: SseEvent,
+    conf: &ResponseMapConfig,
+    headers: &HeaderMap,
+    vars: &mut HashMap<String, Value>,
+) -> SseEvent {
+    // 标量 data 没有字段可映射，展平后会丢失
+    let data = match serde_json::from_str::<Value>(&event.data) {
+        Ok(data @ (Value::Object(_) | Value::Array(_))) => data,
+        _ => return event,
+    };
+    let mut body = HashMap::new();
+    json_to_flat_map(&data, "", &mut body);
+    // header 改动在推流开始后无法生效，使用副本
+    let mut headers = headers.clone();
+    let body_mappings: Vec<MixMapping> = conf
+        .mix_mappings
+        .iter()
+        .filter(|m| m.involves_body())
+        .cloned()
+        .collect();
+    let staged = mapping::run_stage(
+        &body_mappings,
+        conf.script.as_ref(),
+        conf.plugin.as_ref(),
+        "sse",
+        &mut MappingParts {
+            headers: &mut headers,
+            query: None,
+            body: &mut body,
+            vars,
+        },
+    );
+    match staged {
+        Ok(()) => event.data = flat_map_to_json(&body).to_string(),
+        Err(e) => event!(Level::WARN, "SSE event mapping failed, pass through: {}", e),
+    }
+    event
+}
+
+/// 将上游字节流解析为事件，逐事件映射后重新序列化
+pub fn mapped_stream<S, E>(
+    upstream: S,
+    conf: ResponseMapConfig,
+    headers: HeaderMap,
+    mut vars: HashMap<String, Value>,
+) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: std::fmt::Display,
+{
+    async_stream::stream! {
+        let mut parser = SseParser::default();
+        let mut upstream = std::pin::pin!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    event!(Level::ERROR, "SSE upstream error: {}", e);
+                    yield Ok(error_event(&e.to_string()).to_frame().into_bytes());
+                    return;
+                }
+            };
+            for event in parser.push(&chunk) {
+                let event = map_event(event, &conf, &headers, &mut vars);
+                event!(Level::DEBUG, "SSE event: {:?}", event);
+                yield Ok(event.to_frame().into_bytes());
+            }
+        }
+        if let Some(event) = parser.finish() {
+            yield Ok(map_event(event, &conf, &headers, &mut vars).to_frame().into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_events_split_across_chunks() {
+        let mut parser = SseParser::default();
+        assert!(parser
+            .push(b"event: message\r\ndata: {\"answer\":\"he")
+            .is_empty());
+        let events = parser.push(b"llo\"}\r\n\r\n: ping\n\ndata: a\ndata: b\nid: 7\n\n");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message".to_string()),
+                    data: "{\"answer\":\"hello\"}".to_string(),
+                    ..Default::default()
+                },
+                SseEvent {
+                    data: "a\nb".to_string(),
+                    id: Some("7".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(events[1].to_frame(), "id: 7\ndata: a\ndata: b\n\n");
+        let ping = SseEvent {
+            event: Some("ping".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ping.to_frame(), "event: ping\n\n");
+    }
+
+    #[test]
+    fn detect_stream_request() {
+        let mut headers = HeaderMap::new();
+        let mut body = HashMap::from([("stream".to_string(), Value::Bool(true))]);
+        let parts = MappingParts {
+            headers: &mut headers,
+            query: None,
+            body: &mut body,
+            vars: &mut HashMap::new(),
+        };
+        let source = legacy_source("bodyfield-stream");
+        assert!(request_wants_stream(None, source.as_ref(), &parts));
+        assert!(!request_wants_stream(None, legacy_source("stream").as_ref(), &parts));
+        assert_eq!(
+            legacy_source("header-x-use-stream"),
+            Some(MixSource::Header("x-use-stream".to_string()))
+        );
+        let accept = HeaderValue::from_static("text/event-stream");
+        assert!(request_wants_stream(Some(&accept), None, &parts));
+    }
+
+    #[test]
+    fn sse_response_follows_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(is_sse_response(&headers, StatusCode::OK, true));
+        assert!(!is_sse_response(&headers, StatusCode::OK, false));
+        assert!(!is_sse_response(&headers, StatusCode::BAD_GATEWAY, true));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_sse_response(&headers, StatusCode::OK, true));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream; charset=utf-8"),
+        );
+        assert!(is_sse_response(&headers, StatusCode::OK, false));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_and_error_frame() {
+        let upstream = async_stream::stream! {
+            yield Ok::<Bytes, String>(Bytes::from("data: a\n\n"));
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            yield Err("connection reset".to_string());
+            yield Ok(Bytes::from("data: never\n\n"));
+        };
+        let frames: Vec<String> =
+            keepalive(passthrough_stream(upstream, false), &SseConfig::default())
+                .map(|f| String::from_utf8(f.unwrap()).unwrap())
+                .collect()
+                .await;
+        assert_eq!(
+            frames,
+            vec![
+                "data: a\n\n",
+                ": keep-alive\n\n",
+                ": keep-alive\n\n",
+                "\n\nevent: error\ndata: {\"message\":\"connection reset\"}\n\n",
+            ]
+        );
+
+        // 上游停在事件中间：不插心跳，error 事件不与半个事件拼接
+        let upstream = async_stream::stream! {
+            yield Ok::<Bytes, String>(Bytes::from("data: {\"answer\":"));
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            yield Err("connection reset".to_string());
+        };
+        let frames: Vec<u8> = keepalive(passthrough_stream(upstream, false), &SseConfig::default())
+            .map(|f| f.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat();
+        let events = SseParser::default().push(&frames);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, "{\"answer\":");
+        assert_eq!(events[1].event.as_deref(), Some("error"));
+
+        // 压缩的流不插入任何事件
+        let upstream = async_stream::stream! {
+            yield Ok::<Bytes, String>(Bytes::from_static(b"\x1f\x8b\x08"));
+            yield Err("connection reset".to_string());
+        };
+        let frames: Vec<Vec<u8>> =
+            keepalive(passthrough_stream(upstream, true), &SseConfig::default())
+                .map(|f| f.unwrap())
+                .collect()
+                .await;
+        assert_eq!(frames, vec![b"\x1f\x8b\x08".to_vec()]);
+    }
+
+    #[test]
+    fn map_event_renames_and_drops_fields() {
+        let conf: ResponseMapConfig = serde_yaml::from_str(
+            r#"
+mix_mappings:
+- source: !bodyfield answer
+  target: !bodyfield content
+  action: move
+- source: !bodyfield metadata
+  target: !bodyfield metadata
+  action: deletesrc
+"#,
+        )
+        .unwrap();
+        let event = SseEvent {
+            event: Some("message".to_string()),
+            data: r#"{"answer":"hi","metadata":{"usage":{"tokens":3}},"id":"m1"}"#.to_string(),
+            ..Default::default()
+        };
+        let mapped = map_event(event, &conf, &HeaderMap::new(), &mut HashMap::new());
+        let data: Value = serde_json::from_str(&mapped.data).unwrap();
+        assert_eq!(data, serde_json::json!({"content": "hi", "id": "m1"}));
+
+        for scalar in ["42", "true", "\"text\"", "null", "[DONE]"] {
+            let event = SseEvent {
+                data: scalar.to_string(),
+                ..Default::default()
+            };
+            let mapped = map_event(event.clone(), &conf, &HeaderMap::new(), &mut HashMap::new());
+            assert_eq!(mapped, event);
+        }
+    }
+}
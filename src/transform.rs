@@ -0,0 +1,367 @@
+use base64::prelude::*;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::{event, Level};
+
+use crate::cache::FileCache;
+use crate::config::{LookupMode, Transformation};
+use crate::mapping::value_to_string;
+use crate::plugin::run_value_plugin;
+use crate::script::run_value_script;
+
+// 处理转换
+pub fn apply_transformations(
+    transformations: &[Transformation],
+    value: &str,
+    dst_value: Option<&str>,
+) -> Option<String> {
+    let mut result = value.to_string();
+
+    for transform in transformations {
+        match transform {
+            Transformation::Base64Decode => {
+                result = BASE64_STANDARD
+                    .decode(&result)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .unwrap_or_default();
+            }
+            Transformation::Base64Encode => {
+                result = BASE64_STANDARD.encode(&result);
+            }
+            Transformation::Split { separator, index } => {
+                result = result
+                    .split(separator)
+                    .nth(*index)
+                    .unwrap_or_default()
+                    .to_string();
+            }
+            Transformation::Replace { from, to } => {
+                result = result.replace(from, to);
+            }
+            Transformation::Format { format } => {
+                result = format!("{}{}", format, result);
+            }
+            Transformation::Append { value } => {
+                result.push_str(value);
+            }
+            Transformation::Merge => {
+                if let Some(d_value) = dst_value {
+                    result.push_str(d_value);
+                }
+            }
+            Transformation::If => {
+                if let Some(d_val) = dst_value {
+                    if !d_val.is_empty() {
+                        result = d_val.to_string();
+                    }
+                }
+            }
+            // Test
+            Transformation::Extract { regex } => {
+                let re = Regex::new(regex).unwrap();
+                result = re
+                    .find(&result)
+                    .map(|mat| mat.as_str().to_string())
+                    .unwrap_or(result);
+            }
+            Transformation::Lowercase => {
+                result = result.to_lowercase();
+            }
+            Transformation::Uppercase => {
+                result = result.to_uppercase();
+            }
+            // 数组操作在 apply_value_transformations 中处理
+            Transformation::Foreach { .. }
+            | Transformation::Filter { .. }
+            | Transformation::Join { .. }
+            | Transformation::First
+            | Transformation::Last
+            | Transformation::Length
+            | Transformation::Script(_)
+            | Transformation::Plugin(_) => {}
+            Transformation::Lookup {
+                map,
+                file,
+                mode,
+                default,
+            } => {
+                result = match lookup_value(map.as_ref(), file.as_deref(), *mode, &result) {
+                    Some(v) => v,
+                    None => default.clone().unwrap_or(result),
+                };
+            }
+        }
+
+        if result.is_empty() {
+            return None;
+        }
+    }
+
+    Some(result)
+}
+
+// 对 json 值做转换：数组操作作用于整个数组，其他转换对数组逐元素处理，
+// 转换结果为空的元素会被丢弃；数组被过滤为空时返回空数组。
+// 对象不做字符串转换，原样保留；length 对 null 为 0，对其他非数组值为 1
+pub fn apply_value_transformations(
+    transformations: &[Transformation],
+    value: &Value,
+    dst_value: Option<&str>,
+) -> Option<Value> {
+    let mut result = value.clone();
+
+    for transform in transformations {
+        result = match (transform, result) {
+            (Transformation::Foreach { transformations }, Value::Array(arr)) => Value::Array(
+                arr.iter()
+                    .filter_map(|v| apply_value_transformations(transformations, v, dst_value))
+                    .collect(),
+            ),
+            (Transformation::Foreach { transformations }, v) => {
+                apply_value_transformations(transformations, &v, dst_value)?
+            }
+            (Transformation::Filter { regex, invert }, v) => {
+                let keep = |v: &Value| regex.0.is_match(&value_to_string(v)) != *invert;
+                match v {
+                    Value::Array(arr) => Value::Array(arr.into_iter().filter(keep).collect()),
+                    v if keep(&v) => v,
+                    _ => return None,
+                }
+            }
+            (Transformation::Script(conf), v) => run_value_script(conf, &v, dst_value)?,
+            (Transformation::Plugin(conf), v) => run_value_plugin(conf, &v, dst_value)?,
+            (Transformation::Join { separator }, Value::Array(arr)) => Value::String(
+                arr.iter()
+                    .map(value_to_string)
+                    .collect::<Vec<_>>()
+                    .join(separator),
+            ),
+            (Transformation::First, Value::Array(arr)) => arr.into_iter().next()?,
+            (Transformation::Last, Value::Array(arr)) => arr.into_iter().last()?,
+            (Transformation::Length, Value::Array(arr)) => Value::from(arr.len()),
+            (Transformation::Length, Value::Null) => Value::from(0),
+            (Transformation::Length, _) => Value::from(1),
+            (Transformation::Join { .. } | Transformation::First | Transformation::Last, v) => v,
+            (t, Value::Array(arr)) => Value::Array(
+                arr.iter()
+                    .filter_map(|v| {
+                        apply_value_transformations(std::slice::from_ref(t), v, dst_value)
+                    })
+                    .collect(),
+            ),
+            // 对象没有对应的字符串形式，原样保留
+            (_, Value::Object(obj)) => Value::Object(obj),
+            (_, Value::Null) => return None,
+            (t, v) => Value::String(apply_transformations(
+                std::slice::from_ref(t),
+                &value_to_string(&v),
+                dst_value,
+            )?),
+        };
+    }
+
+    Some(result)
+}
+
+// 字典文件按路径和修改时间缓存
+static LOOKUP_FILES: FileCache<Vec<(String, String)>> = FileCache::new();
+
+// 先查内联 map，再查字典文件
+fn lookup_value(
+    map: Option<&serde_yaml::Mapping>,
+    file: Option<&str>,
+    mode: LookupMode,
+    value: &str,
+) -> Option<String> {
+    let inline = map.map(mapping_entries).unwrap_or_default();
+    let file_table = file.and_then(|file| {
+        LOOKUP_FILES
+            .get(file, load_lookup_file)
+            .map_err(|e| event!(Level::ERROR, "Failed to load lookup file {}: {}", file, e))
+            .ok()
+    });
+    let file_entries = file_table.iter().flat_map(|table| table.iter());
+    lookup(inline.iter().chain(file_entries), mode, value)
+}
+
+fn yaml_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn mapping_entries(map: &serde_yaml::Mapping) -> Vec<(String, String)> {
+    map.iter()
+        .filter_map(|(k, v)| Some((yaml_to_string(k)?, yaml_to_string(v)?)))
+        .collect()
+}
+
+// 字典文件：.csv 为两列（key,value），其他按 yaml map 解析
+fn load_lookup_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)?;
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(content.as_bytes());
+        let mut entries = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            if let (Some(k), Some(v)) = (record.get(0), record.get(1)) {
+                entries.push((k.to_string(), v.to_string()));
+            }
+        }
+        Ok(entries)
+    } else {
+        let map: serde_yaml::Mapping = serde_yaml::from_str(&content)?;
+        Ok(mapping_entries(&map))
+    }
+}
+
+// regex 模式的 key 编译后缓存，无效的正则只记录一次错误
+fn lookup_regex(pattern: &str) -> Option<Arc<Regex>> {
+    static COMPILED: OnceLock<Mutex<HashMap<String, Option<Arc<Regex>>>>> = OnceLock::new();
+    let mut compiled = COMPILED.get_or_init(Default::default).lock().unwrap();
+    compiled
+        .entry(pattern.to_string())
+        .or_insert_with(|| {
+            Regex::new(pattern)
+                .map(Arc::new)
+                .map_err(|e| event!(Level::ERROR, "Invalid lookup regex {}: {}", pattern, e))
+                .ok()
+        })
+        .clone()
+}
+
+// 按配置顺序查找第一个命中的项
+fn lookup<'a>(
+    table: impl IntoIterator<Item = &'a (String, String)>,
+    mode: LookupMode,
+    value: &str,
+) -> Option<String> {
+    table.into_iter().find_map(|(k, v)| match mode {
+        LookupMode::Exact => (value == k).then(|| v.clone()),
+        LookupMode::Prefix => value.starts_with(k.as_str()).then(|| v.clone()),
+        LookupMode::Contains => value.contains(k.as_str()).then(|| v.clone()),
+        LookupMode::Regex => {
+            let re = lookup_regex(k)?;
+            // 支持在 value 中引用捕获组，如 "$1"
+            re.captures(value).map(|caps| {
+                let mut out = String::new();
+                caps.expand(v, &mut out);
+                out
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn role_lookup(mode: LookupMode, default: Option<&str>) -> Transformation {
+        let map: serde_yaml::Mapping =
+            serde_yaml::from_str("\"id=Admin,id=TATTOO\": admin\n\"id=User,id=TATTOO\": normal")
+                .unwrap();
+        Transformation::Lookup {
+            map: Some(map),
+            file: None,
+            mode,
+            default: default.map(String::from),
+        }
+    }
+
+    #[test]
+    fn lookup_prefix_and_default() {
+        let trans = [role_lookup(LookupMode::Prefix, Some("guest"))];
+        assert_eq!(
+            apply_transformations(&trans, "id=Admin,id=TATTOO,id=appRole,o=decathlon", None),
+            Some("admin".to_string())
+        );
+        assert_eq!(
+            apply_transformations(&trans, "id=Other,id=TATTOO", None),
+            Some("guest".to_string())
+        );
+    }
+
+    #[test]
+    fn lookup_on_array_drops_unmatched() {
+        let trans = [role_lookup(LookupMode::Prefix, Some(""))];
+        let roles = json!([
+            "id=SFA,id=CZ",
+            "id=User,id=TATTOO,ou=role",
+            "id=Admin,id=TATTOO"
+        ]);
+        assert_eq!(
+            apply_value_transformations(&trans, &roles, None),
+            Some(json!(["normal", "admin"]))
+        );
+    }
+
+    #[test]
+    fn array_filter_foreach_join() {
+        let trans: Vec<Transformation> = serde_yaml::from_str(
+            r#"
+- type: filter
+  regex: "id=TATTOO"
+- type: foreach
+  transformations:
+  - type: split
+    separator: ","
+    index: 0
+  - type: replace
+    from: "id="
+    to: ""
+  - type: lowercase
+- type: join
+  separator: ","
+"#,
+        )
+        .unwrap();
+        let roles = json!(["id=Admin,id=TATTOO", "id=SFA,id=CZ", "id=User,id=TATTOO"]);
+        assert_eq!(
+            apply_value_transformations(&trans, &roles, None),
+            Some(json!("admin,user"))
+        );
+        assert_eq!(
+            apply_value_transformations(&[Transformation::Length], &roles, None),
+            Some(json!(3))
+        );
+        assert_eq!(
+            apply_value_transformations(&[Transformation::Last], &roles, None),
+            Some(json!("id=User,id=TATTOO"))
+        );
+    }
+
+    #[test]
+    fn length_of_null_and_object_passthrough() {
+        assert_eq!(
+            apply_value_transformations(&[Transformation::Length], &Value::Null, None),
+            Some(json!(0))
+        );
+        let user = json!({"name": "irene"});
+        assert_eq!(
+            apply_value_transformations(&[Transformation::Length], &user, None),
+            Some(json!(1))
+        );
+        assert_eq!(
+            apply_value_transformations(&[Transformation::Uppercase], &user, None),
+            Some(user)
+        );
+    }
+
+    #[test]
+    fn invalid_filter_regex_fails_config_parsing() {
+        let parsed: Result<Vec<Transformation>, _> =
+            serde_yaml::from_str("- type: filter\n  regex: \"id=(\"");
+        assert!(parsed.is_err());
+    }
+}
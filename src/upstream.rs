@@ -0,0 +1,463 @@
+// 命名上游：client credentials token 的获取、缓存与注入，并发限制与排队
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use hyper::{header, header::HeaderName, header::HeaderValue, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{event, Level};
+
+use crate::config::{OAuth2ClientAuth, UpstreamAuthConfig, UpstreamConfig};
+use crate::metrics::{Metrics, Sample};
+use crate::oauth2::{normalize_token_response, parse_upstream_body};
+
+#[derive(Clone)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+// 每组凭据一个槽位，refresh 锁保证同时只有一个刷新请求
+#[derive(Default)]
+struct TokenSlot {
+    token: Mutex<Option<CachedToken>>,
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl TokenSlot {
+    fn current(&self) -> Option<CachedToken> {
+        self.token.lock().unwrap().clone()
+    }
+}
+
+fn slots() -> &'static Mutex<HashMap<String, Arc<TokenSlot>>> {
+    static SLOTS: OnceLock<Mutex<HashMap<String, Arc<TokenSlot>>>> = OnceLock::new();
+    SLOTS.get_or_init(Default::default)
+}
+
+fn cache_key(conf: &UpstreamAuthConfig) -> String {
+    format!(
+        "{}|{}|{}|{}",
+        conf.token_url,
+        conf.client_id,
+        conf.scope.as_deref().unwrap_or_default(),
+        conf.audience.as_deref().unwrap_or_default()
+    )
+}
+
+fn slot(conf: &UpstreamAuthConfig) -> Arc<TokenSlot> {
+    slots()
+        .lock()
+        .unwrap()
+        .entry(cache_key(conf))
+        .or_default()
+        .clone()
+}
+
+async fn fetch_token(conf: &UpstreamAuthConfig, client: &Client) -> anyhow::Result<CachedToken> {
+    let mut params = vec![("grant_type", "client_credentials")];
+    if let Some(scope) = &conf.scope {
+        params.push(("scope", scope));
+    }
+    if let Some(audience) = &conf.audience {
+        params.push(("audience", audience));
+    }
+    let mut request = client.post(&conf.token_url);
+    match conf.client_auth {
+        OAuth2ClientAuth::Params => {
+            params.push(("client_id", &conf.client_id));
+            params.push(("client_secret", &conf.client_secret));
+        }
+        OAuth2ClientAuth::Basic => {
+            let credentials = format!(
+                "{}:{}",
+                form_urlencoded_component(&conf.client_id),
+                form_urlencoded_component(&conf.client_secret)
+            );
+            request = request.header(
+                header::AUTHORIZATION,
+                format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+            );
+        }
+    }
+    let response = request.form(&params).send().await?;
+    let status = StatusCode::from_u16(response.status().as_u16())?;
+    let body = parse_upstream_body(&response.bytes().await?);
+    let (status, token) = normalize_token_response(status, body);
+    let access_token = token["access_token"].as_str().unwrap_or_default();
+    if !status.is_success() || access_token.is_empty() {
+        anyhow::bail!("token endpoint returned {}: {}", status, token);
+    }
+    let expires_in = token["expires_in"]
+        .as_u64()
+        .unwrap_or(conf.default_expires_in);
+    Ok(CachedToken {
+        access_token: access_token.to_string(),
+        expires_at: Instant::now() + Duration::from_secs(expires_in),
+    })
+}
+
+// RFC 6749 2.3.1 Basic 认证前对 client_id、client_secret 做 form 编码
+fn form_urlencoded_component(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+/// 返回缓存的 access token，临近过期时刷新，并发请求共用同一次刷新
+pub async fn access_token(conf: &UpstreamAuthConfig, client: &Client) -> anyhow::Result<String> {
+    let slot = slot(conf);
+    let refresh_before = Duration::from_secs(conf.refresh_before_secs);
+    let fresh = |token: &CachedToken| Instant::now() + refresh_before < token.expires_at;
+
+    let current = slot.current();
+    if let Some(token) = current.as_ref().filter(|t| fresh(t)) {
+        return Ok(token.access_token.clone());
+    }
+    // 仍在有效期内且已有刷新进行中时，继续使用当前 token
+    let valid = current.filter(|t| Instant::now() < t.expires_at);
+    let _guard = match (valid, slot.refresh.try_lock()) {
+        (_, Ok(guard)) => guard,
+        (Some(token), Err(_)) => return Ok(token.access_token),
+        (None, Err(_)) => slot.refresh.lock().await,
+    };
+    // 等待期间其他请求可能已刷新
+    if let Some(token) = slot.current().filter(|t| fresh(t)) {
+        return Ok(token.access_token);
+    }
+
+    event!(
+        Level::DEBUG,
+        "Fetching upstream token from {}",
+        conf.token_url
+    );
+    let token = fetch_token(conf, client).await?;
+    *slot.token.lock().unwrap() = Some(token.clone());
+    Ok(token.access_token)
+}
+
+/// 上游拒绝 token 时丢弃缓存，下次请求重新获取
+pub fn invalidate(conf: &UpstreamAuthConfig) {
+    *slot(conf).token.lock().unwrap() = None;
+}
+
+/// 获取 token 并写入上游请求 header
+pub async fn apply_auth(
+    conf: &UpstreamAuthConfig,
+    client: &Client,
+    headers: &mut HeaderMap,
+) -> Result<(), (StatusCode, String)> {
+    let token = access_token(conf, client).await.map_err(|e| {
+        event!(Level::ERROR, "Upstream token request failed: {}", e);
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Upstream token request failed: {}", e),
+        )
+    })?;
+    let name = HeaderName::try_from(conf.header.as_str())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let value = HeaderValue::from_str(&format!("{}{}", conf.prefix, token))
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    headers.insert(name, value);
+    Ok(())
+}
+
+// 上游的并发限制，配置的并发数变化时调整同一个信号量的许可数
+struct Limiter {
+    sizing: Mutex<Sizing>,
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+    shed: AtomicU64,
+}
+
+struct Sizing {
+    max_concurrent: usize,
+    // 调小并发数时被占用、尚未收回的许可
+    excess: usize,
+}
+
+impl Limiter {
+    fn max_concurrent(&self) -> usize {
+        self.sizing.lock().unwrap().max_concurrent
+    }
+
+    fn resize(&self, max_concurrent: usize) {
+        let mut sizing = self.sizing.lock().unwrap();
+        if max_concurrent > sizing.max_concurrent {
+            // 先抵消未收回的许可，不足的部分再增加
+            let added = max_concurrent - sizing.max_concurrent;
+            let cancelled = added.min(sizing.excess);
+            sizing.excess -= cancelled;
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            sizing.excess += sizing.max_concurrent - max_concurrent;
+        }
+        sizing.max_concurrent = max_concurrent;
+        Self::reclaim(&self.semaphore, &mut sizing);
+    }
+
+    // 收回已释放的多余许可
+    fn reclaim(semaphore: &Semaphore, sizing: &mut Sizing) {
+        if sizing.excess > 0 {
+            sizing.excess -= semaphore.forget_permits(sizing.excess);
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        let mut sizing = self.sizing.lock().unwrap();
+        Self::reclaim(&self.semaphore, &mut sizing);
+        (sizing.max_concurrent + sizing.excess).saturating_sub(self.semaphore.available_permits())
+    }
+}
+
+fn limiters() -> &'static Mutex<HashMap<String, Arc<Limiter>>> {
+    static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<Limiter>>>> = OnceLock::new();
+    LIMITERS.get_or_init(Default::default)
+}
+
+fn limiter(name: &str, max_concurrent: usize) -> Arc<Limiter> {
+    let mut limiters = limiters().lock().unwrap();
+    let limiter = limiters.entry(name.to_string()).or_insert_with(|| {
+        Arc::new(Limiter {
+            sizing: Mutex::new(Sizing {
+                max_concurrent,
+                excess: 0,
+            }),
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            queued: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        })
+    });
+    // 重新加载后并发数变化时，进行中的请求仍占用同一个信号量
+    limiter.resize(max_concurrent);
+    limiter.clone()
+}
+
+// 离开队列时（包括客户端断开）减少排队数
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 并发已满且队列已满或排队超时
+#[derive(Debug)]
+pub struct Overloaded;
+
+/// 获取上游的并发许可，许可释放前计为进行中的请求；未配置并发限制时返回 None
+pub async fn acquire_permit(
+    name: &str,
+    conf: &UpstreamConfig,
+) -> Result<Option<OwnedSemaphorePermit>, Overloaded> {
+    let Some(max_concurrent) = conf.max_concurrent else {
+        return Ok(None);
+    };
+    let limiter = limiter(name, max_concurrent);
+    if let Ok(permit) = limiter.semaphore.clone().try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+    if limiter.queued.fetch_add(1, Ordering::SeqCst) >= conf.max_queue {
+        limiter.queued.fetch_sub(1, Ordering::SeqCst);
+        limiter.shed.fetch_add(1, Ordering::Relaxed);
+        return Err(Overloaded);
+    }
+    let _queued = QueueGuard(&limiter.queued);
+    let wait = Duration::from_millis(conf.queue_timeout_ms);
+    match tokio::time::timeout(wait, limiter.semaphore.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(Some(permit)),
+        _ => {
+            limiter.shed.fetch_add(1, Ordering::Relaxed);
+            Err(Overloaded)
+        }
+    }
+}
+
+/// 过载时的 503 响应
+pub fn overloaded_response() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+        Json(json!({"error": "upstream_overloaded"})),
+    )
+        .into_response()
+}
+
+/// 上游的并发状态，未配置并发限制或未请求过时为 None
+pub fn limiter_status(name: &str) -> Option<Value> {
+    let limiter = limiters().lock().unwrap().get(name)?.clone();
+    Some(json!({
+        "max_concurrent": limiter.max_concurrent(),
+        "in_flight": limiter.in_flight(),
+        "queued": limiter.queued.load(Ordering::SeqCst),
+        "shed_total": limiter.shed.load(Ordering::Relaxed),
+    }))
+}
+
+/// 输出各上游的并发、排队和丢弃数
+pub fn write_metrics(metrics: &mut Metrics) {
+    let mut limiters: Vec<(String, Arc<Limiter>)> = limiters()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    limiters.sort_by(|a, b| a.0.cmp(&b.0));
+    let samples = |value: fn(&Limiter) -> f64| -> Vec<Sample> {
+        limiters
+            .iter()
+            .map(|(name, limiter)| (vec![("upstream", name.as_str())], value(limiter)))
+            .collect()
+    };
+    metrics.gauge(
+        "sso_adapter_upstream_max_concurrent",
+        "Configured concurrency limit per upstream.",
+        &samples(|l| l.max_concurrent() as f64),
+    );
+    metrics.gauge(
+        "sso_adapter_upstream_in_flight",
+        "Requests currently holding an upstream permit.",
+        &samples(|l| l.in_flight() as f64),
+    );
+    metrics.gauge(
+        "sso_adapter_upstream_queued",
+        "Requests waiting for an upstream permit.",
+        &samples(|l| l.queued.load(Ordering::SeqCst) as f64),
+    );
+    metrics.counter(
+        "sso_adapter_upstream_shed_total",
+        "Requests rejected with 503 because the upstream was saturated.",
+        &samples(|l| l.shed.load(Ordering::Relaxed) as f64),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::post, Form, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // 本地 mock token 端点，记录调用次数，client_id 为 short 时 30 秒过期
+    async fn mock_token_endpoint() -> (String, Arc<AtomicUsize>) {
+        async fn token(
+            State(calls): State<Arc<AtomicUsize>>,
+            Form(params): Form<HashMap<String, String>>,
+        ) -> String {
+            let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(params["grant_type"], "client_credentials");
+            let expires_in = if params["client_id"] == "short" {
+                30
+            } else {
+                3600
+            };
+            serde_json::json!({
+                "access_token": format!("t{}", n),
+                "token_type": "bearer",
+                "expires_in": expires_in.to_string(),
+            })
+            .to_string()
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/token", post(token))
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/token", addr), calls)
+    }
+
+    fn auth_config(token_url: &str, client_id: &str) -> UpstreamAuthConfig {
+        serde_yaml::from_str(&format!(
+            "{{token_url: '{}', client_id: {}, client_secret: s3cr3t}}",
+            token_url, client_id
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_fetch() {
+        let (token_url, calls) = mock_token_endpoint().await;
+        let conf = auth_config(&token_url, "app");
+        let client = Client::new();
+        let tokens =
+            futures_util::future::join_all((0..10).map(|_| access_token(&conf, &client))).await;
+        assert!(tokens.iter().all(|t| t.as_deref().ok() == Some("t1")));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let mut headers = HeaderMap::new();
+        apply_auth(&conf, &client, &mut headers).await.unwrap();
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer t1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        invalidate(&conf);
+        assert_eq!(access_token(&conf, &client).await.unwrap(), "t2");
+    }
+
+    #[tokio::test]
+    async fn refresh_ahead_of_expiry() {
+        let (token_url, calls) = mock_token_endpoint().await;
+        // 30 秒过期，距过期不足 60 秒时刷新
+        let conf = auth_config(&token_url, "short");
+        let client = Client::new();
+        assert_eq!(access_token(&conf, &client).await.unwrap(), "t1");
+        assert_eq!(access_token(&conf, &client).await.unwrap(), "t2");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_queue_and_shed() {
+        let conf: UpstreamConfig =
+            serde_yaml::from_str("{max_concurrent: 1, max_queue: 1, queue_timeout_ms: 100}")
+                .unwrap();
+        let first = acquire_permit("limited", &conf).await.unwrap().unwrap();
+
+        // 第二个请求排队，第三个请求队列已满直接丢弃
+        let queued = tokio::spawn({
+            let conf = conf.clone();
+            async move { acquire_permit("limited", &conf).await.map(|p| p.is_some()) }
+        });
+        tokio::task::yield_now().await;
+        assert!(acquire_permit("limited", &conf).await.is_err());
+        drop(first);
+        assert!(queued.await.unwrap().unwrap());
+
+        // 排队超时
+        let _held = acquire_permit("limited", &conf).await.unwrap();
+        assert!(acquire_permit("limited", &conf).await.is_err());
+
+        let text = crate::metrics::render();
+        assert!(text.contains("sso_adapter_upstream_in_flight{upstream=\"limited\"} 1"));
+        assert!(text.contains("sso_adapter_upstream_queued{upstream=\"limited\"} 0"));
+        assert!(text.contains("sso_adapter_upstream_shed_total{upstream=\"limited\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn resize_keeps_in_flight_permits() {
+        let conf = |max: usize| -> UpstreamConfig {
+            serde_yaml::from_str(&format!("{{max_concurrent: {}, max_queue: 0}}", max)).unwrap()
+        };
+        let first = acquire_permit("resized", &conf(2)).await.unwrap();
+        let second = acquire_permit("resized", &conf(2)).await.unwrap();
+
+        // 调小后进行中的请求仍计入限制，释放后才收回多余的许可
+        assert!(acquire_permit("resized", &conf(1)).await.is_err());
+        assert_eq!(limiter_status("resized").unwrap()["in_flight"], 2);
+        drop(first);
+        assert!(acquire_permit("resized", &conf(1)).await.is_err());
+        drop(second);
+        let held = acquire_permit("resized", &conf(1)).await.unwrap();
+        assert!(acquire_permit("resized", &conf(1)).await.is_err());
+
+        // 调大后立即增加许可
+        let _more = acquire_permit("resized", &conf(3)).await.unwrap();
+        let _most = acquire_permit("resized", &conf(3)).await.unwrap();
+        assert!(acquire_permit("resized", &conf(3)).await.is_err());
+        drop(held);
+        assert_eq!(limiter_status("resized").unwrap()["in_flight"], 2);
+    }
+}
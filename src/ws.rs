@@ -0,0 +1,313 @@
+use axum::{
+    extract::ws::{self, WebSocket, WebSocketUpgrade},
+    http::StatusCode,
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{header, HeaderMap};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+    Connector,
+};
+use tracing::{event, Level};
+
+use crate::config::{MessageMapConfig, PathConfig};
+use crate::mapping::{self, MappingParts, StageError};
+use crate::{flat_map_to_json, json_to_flat_map, missing_field_response, query_to_multimap};
+
+// 由 websocket 握手自行生成，不转发的 header
+const HANDSHAKE_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-accept",
+    "content-length",
+];
+
+/// http(s) 目标地址转换为 ws(s) 地址，无 scheme 时使用 ws
+pub fn ws_url(base_url: &str, path: &str, query: &HashMap<String, Vec<String>>) -> String {
+    let base = match base_url.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some((_, rest)) => format!("ws://{}", rest),
+        None => format!("ws://{}", base_url),
+    };
+    if query.is_empty() {
+        format!("{}{}", base, path)
+    } else {
+        format!("{}{}?{}", base, path, crate::multimap_to_query(query))
+    }
+}
+
+/// 对 json 文本消息执行消息映射，非 json 或映射失败时原样返回
+pub fn map_message(
+    text: &str,
+    conf: &MessageMapConfig,
+    headers: &HeaderMap,
+    vars: &mut HashMap<String, Value>,
+) -> String {
+    let Ok(data) = serde_json::from_str::<Value>(text) else {
+        return text.to_string();
+    };
+    let mut body = HashMap::new();
+    json_to_flat_map(&data, "", &mut body);
+    let staged = mapping::run_stage(
+        &conf.mix_mappings,
+        conf.script.as_ref(),
+        conf.plugin.as_ref(),
+        "message",
+        &mut MappingParts {
+            headers: &mut headers.clone(),
+            query: None,
+            body: &mut body,
+            vars,
+        },
+    );
+    match staged {
+        Ok(()) => flat_map_to_json(&body).to_string(),
+        Err(e) => {
+            event!(Level::WARN, "WebSocket message mapping failed, pass through: {}", e);
+            text.to_string()
+        }
+    }
+}
+
+/// 握手阶段执行 header/query 映射后连接上游，连接成功再升级客户端连接
+pub async fn proxy(
+    upgrade: WebSocketUpgrade,
+    config: Option<PathConfig>,
+    base_url: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    mut vars: HashMap<String, Value>,
+) -> Result<Response, (StatusCode, String)> {
+    let mut headers_map = headers.clone();
+    let mut query_map = query.map(query_to_multimap).unwrap_or_default();
+
+    if let Some(conf) = &config {
+        let staged = mapping::run_stage(
+            &mapping::header_mappings(&conf.request.mix_mappings),
+            None,
+            None,
+            "handshake",
+            &mut MappingParts {
+                headers: &mut headers_map,
+                query: Some(&mut query_map),
+                body: &mut HashMap::new(),
+                vars: &mut vars,
+            },
+        );
+        match staged {
+            Ok(()) => {}
+            Err(StageError::MissingField(field)) => {
+                return Ok(missing_field_response(
+                    conf.request.missing_response.as_ref(),
+                    &field,
+                ));
+            }
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        }
+    }
+
+    let url = ws_url(base_url, path, &query_map);
+    event!(Level::INFO, "WebSocket upgrade to {}", url);
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Invalid upstream url: {}", e)))?;
+    for (name, value) in headers_map.iter() {
+        if !HANDSHAKE_HEADERS.contains(&name.as_str()) {
+            request.headers_mut().append(name, value.clone());
+        }
+    }
+
+    let tls = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_hostnames(true)
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let (upstream, handshake) =
+        connect_async_tls_with_config(request, None, false, Some(Connector::NativeTls(tls)))
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    format!("WebSocket connect failed: {}", e),
+                )
+            })?;
+
+    // 使用上游选定的子协议
+    let upgrade = match handshake
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(protocol) => upgrade.protocols([protocol.to_string()]),
+        None => upgrade,
+    };
+
+    let mut res_headers_map = HeaderMap::new();
+    for (name, value) in handshake.headers().iter() {
+        if !HANDSHAKE_HEADERS.contains(&name.as_str())
+            && name != header::SEC_WEBSOCKET_PROTOCOL
+        {
+            res_headers_map.append(name, value.clone());
+        }
+    }
+    if let Some(conf) = &config {
+        mapping::apply_mix_mappings(
+            &mapping::header_mappings(&conf.response.mix_mappings),
+            &mut MappingParts {
+                headers: &mut res_headers_map,
+                query: None,
+                body: &mut HashMap::new(),
+                vars: &mut vars,
+            },
+        )
+        .map_err(|e| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Missing required response field: {}", e.0),
+            )
+        })?;
+    }
+
+    let req_conf = config.as_ref().and_then(|c| c.request.message.clone());
+    let res_conf = config.as_ref().and_then(|c| c.response.message.clone());
+    let mut response = upgrade.on_upgrade(move |client| async move {
+        pump(client, upstream, req_conf, res_conf, headers_map, vars).await;
+    });
+    response.headers_mut().extend(res_headers_map);
+    Ok(response)
+}
+
+fn to_upstream(msg: ws::Message) -> tungstenite::Message {
+    match msg {
+        ws::Message::Text(t) => tungstenite::Message::text(t.as_str()),
+        ws::Message::Binary(b) => tungstenite::Message::Binary(b),
+        ws::Message::Ping(b) => tungstenite::Message::Ping(b),
+        ws::Message::Pong(b) => tungstenite::Message::Pong(b),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|f| tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(f.code),
+                reason: f.reason.as_str().into(),
+            }))
+        }
+    }
+}
+
+fn to_client(msg: tungstenite::Message) -> Option<ws::Message> {
+    Some(match msg {
+        tungstenite::Message::Text(t) => ws::Message::text(t.as_str()),
+        tungstenite::Message::Binary(b) => ws::Message::Binary(b),
+        tungstenite::Message::Ping(b) => ws::Message::Ping(b),
+        tungstenite::Message::Pong(b) => ws::Message::Pong(b),
+        tungstenite::Message::Close(frame) => ws::Message::Close(frame.map(|f| ws::CloseFrame {
+            code: f.code.into(),
+            reason: f.reason.as_str().into(),
+        })),
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+// 双向转发消息，任一方向结束时关闭两端
+async fn pump<S>(
+    client: WebSocket,
+    upstream: tokio_tungstenite::WebSocketStream<S>,
+    req_conf: Option<MessageMapConfig>,
+    res_conf: Option<MessageMapConfig>,
+    headers: HeaderMap,
+    vars: HashMap<String, Value>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+
+    let mut req_vars = vars.clone();
+    let client_to_upstream = async {
+        while let Some(Ok(msg)) = client_rx.next().await {
+            let msg = match (msg, &req_conf) {
+                (ws::Message::Text(t), Some(conf)) => {
+                    ws::Message::text(map_message(t.as_str(), conf, &headers, &mut req_vars))
+                }
+                (msg, _) => msg,
+            };
+            if upstream_tx.send(to_upstream(msg)).await.is_err() {
+                break;
+            }
+        }
+        let _ = upstream_tx.close().await;
+    };
+
+    let mut res_vars = vars;
+    let upstream_to_client = async {
+        while let Some(Ok(msg)) = upstream_rx.next().await {
+            let Some(msg) = to_client(msg) else { continue };
+            let msg = match (msg, &res_conf) {
+                (ws::Message::Text(t), Some(conf)) => {
+                    ws::Message::text(map_message(t.as_str(), conf, &headers, &mut res_vars))
+                }
+                (msg, _) => msg,
+            };
+            if client_tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = client_tx.close().await;
+    };
+
+    tokio::select! {
+        _ = client_to_upstream => event!(Level::DEBUG, "WebSocket client closed"),
+        _ = upstream_to_client => event!(Level::DEBUG, "WebSocket upstream closed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_url_uses_ws_scheme() {
+        let query = HashMap::from([("token".to_string(), vec!["a b".to_string()])]);
+        assert_eq!(
+            ws_url("https://dify.local", "/ws", &query),
+            "wss://dify.local/ws?token=a+b"
+        );
+        assert_eq!(
+            ws_url("dify.local:8091", "/ws", &HashMap::new()),
+            "ws://dify.local:8091/ws"
+        );
+    }
+
+    #[test]
+    fn map_json_text_message() {
+        let conf: MessageMapConfig = serde_yaml::from_str(
+            r#"
+mix_mappings:
+- source: !bodyfield text
+  target: !bodyfield query
+  action: move
+"#,
+        )
+        .unwrap();
+        let mapped = map_message(
+            r#"{"text":"hi","user":"u1"}"#,
+            &conf,
+            &HeaderMap::new(),
+            &mut HashMap::new(),
+        );
+        let data: Value = serde_json::from_str(&mapped).unwrap();
+        assert_eq!(data, serde_json::json!({"query": "hi", "user": "u1"}));
+        assert_eq!(
+            map_message("ping", &conf, &HeaderMap::new(), &mut HashMap::new()),
+            "ping"
+        );
+    }
+}